@@ -0,0 +1,128 @@
+// Generates the symbol table the kernel embeds for its backtraces, see src/backtrace.rs
+//
+// The table is built from the `nm --defined-only -S -C` output of a previous build of
+// the kernel, read from the file named by the KERNEL_SYMBOLS environment variable.
+// Without it the table is empty and backtraces only show addresses. The table always
+// has the same size, so embedding the symbols doesn't move the code they describe:
+// building twice, with the symbols of the first build, gives a matching table. The
+// makefile does that.
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+// Size of the table in bytes, must match SYMBOL_TABLE_SIZE in src/backtrace.rs
+const SYMBOL_TABLE_SIZE: usize = 1 << 20;
+// Bytes of a table entry: address (u64), size, name offset and name length (u32 each)
+const ENTRY_SIZE: usize = 20;
+// Bytes of the header holding the number of entries (u32)
+const HEADER_SIZE: usize = 4;
+
+struct Symbol {
+    addr: u64,
+    size: u32,
+    name: String,
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=KERNEL_SYMBOLS");
+
+    let mut symbols = match env::var("KERNEL_SYMBOLS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            read_symbols(&path)
+        }
+        Err(_) => Vec::new(),
+    };
+    symbols.sort_by_key(|symbol| symbol.addr);
+    // aliases of the same code, e.g. merged functions, only keep the first name
+    symbols.dedup_by_key(|symbol| symbol.addr);
+
+    let table = encode(&symbols);
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
+    let mut file = File::create(Path::new(&out_dir).join("symbols.bin"))
+        .expect("failed to create symbols.bin");
+    file.write_all(&table).expect("failed to write symbols.bin");
+}
+
+// Reads the functions from the nm output. A missing file gives an empty table, e.g.
+// for the first of the two builds
+fn read_symbols(path: &str) -> Vec<Symbol> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut text).expect("failed to read the kernel symbols");
+        }
+        Err(_) => return Vec::new(),
+    }
+    text.lines().filter_map(parse_line).collect()
+}
+
+// Parses a line "address size type name" of nm, keeping only code symbols with a size.
+// Demangled names contain spaces, so the name is the rest of the line
+fn parse_line(line: &str) -> Option<Symbol> {
+    let mut fields = line.splitn(4, ' ');
+    let addr = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let size = u32::from_str_radix(fields.next()?, 16).ok()?;
+    match fields.next()? {
+        "t" | "T" | "w" | "W" => {}
+        _ => return None,
+    }
+    let name = strip_hash(fields.next()?.trim());
+    if size == 0 || name.is_empty() {
+        return None;
+    }
+    Some(Symbol { addr, size, name: name.to_string() })
+}
+
+// Removes the "::h0123456789abcdef" hash suffix of demangled Rust symbols
+fn strip_hash(name: &str) -> &str {
+    let hash_len = "::h".len() + 16;
+    if name.len() > hash_len {
+        let (prefix, suffix) = name.split_at(name.len() - hash_len);
+        if suffix.starts_with("::h") && suffix[3..].chars().all(|c| c.is_digit(16)) {
+            return prefix;
+        }
+    }
+    name
+}
+
+// Lays out the table: the header, the entries sorted by address, then the names. All
+// integers are little endian, name offsets are from the start of the table. Symbols
+// not fitting are left out with a warning
+fn encode(symbols: &[Symbol]) -> Vec<u8> {
+    let mut count = 0;
+    let mut names_size = 0;
+    for symbol in symbols {
+        let size = HEADER_SIZE + (count + 1) * ENTRY_SIZE + names_size + symbol.name.len();
+        if size > SYMBOL_TABLE_SIZE {
+            println!("cargo:warning=symbol table full, {} of {} symbols left out",
+                     symbols.len() - count, symbols.len());
+            break;
+        }
+        count += 1;
+        names_size += symbol.name.len();
+    }
+
+    let mut table = Vec::with_capacity(SYMBOL_TABLE_SIZE);
+    push_u32(&mut table, count as u32);
+    let mut name_offset = HEADER_SIZE + count * ENTRY_SIZE;
+    for symbol in &symbols[..count] {
+        table.extend_from_slice(&symbol.addr.to_le_bytes());
+        push_u32(&mut table, symbol.size);
+        push_u32(&mut table, name_offset as u32);
+        push_u32(&mut table, symbol.name.len() as u32);
+        name_offset += symbol.name.len();
+    }
+    for symbol in &symbols[..count] {
+        table.extend_from_slice(symbol.name.as_bytes());
+    }
+    table.resize(SYMBOL_TABLE_SIZE, 0);
+    table
+}
+
+fn push_u32(table: &mut Vec<u8>, value: u32) {
+    table.extend_from_slice(&value.to_le_bytes());
+}
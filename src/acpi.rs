@@ -0,0 +1,327 @@
+use alloc::vec::Vec;
+use core::slice;
+use x86_64::PhysAddr;
+use memory::{ioremap, CacheMode};
+
+/// Signature of the root system description pointer, found in the BIOS area.
+pub const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Signature of the multiple APIC description table.
+pub const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// Size of the header every system description table starts with.
+pub const HEADER_SIZE: usize = 36;
+
+// Physical address of the word holding the real mode segment of the extended BIOS
+// data area
+const EBDA_POINTER: u64 = 0x40e;
+// The RSDP is in the first KiB of the EBDA or in the BIOS ROM area
+const EBDA_SEARCH_SIZE: usize = 1024;
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_SIZE: usize = 0x2_0000;
+
+/// The root system description pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub rsdt_address: u32,
+    // only set from revision 2 (ACPI 2.0) on
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    /// Returns the physical address of the root table and the size of its entries,
+    /// preferring the XSDT.
+    pub fn root_table(&self) -> (u64, usize) {
+        match self.xsdt_address {
+            Some(address) => (address, 8),
+            None => (u64::from(self.rsdt_address), 4),
+        }
+    }
+}
+
+/// A local APIC, one for each processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicEntry {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+/// An IOAPIC, serving the global system interrupts from `gsi_base` on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// A legacy ISA interrupt connected to a different global system interrupt, or with
+/// a different polarity or trigger mode than ISA interrupts have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    // MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3
+    pub flags: u16,
+}
+
+/// The interrupt controllers described by the MADT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    // the system also has 8259 PICs, which must be masked to use the APICs
+    pub has_legacy_pics: bool,
+    pub local_apics: Vec<LocalApicEntry>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from(bytes[offset]) | u16::from(bytes[offset + 1]) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(bytes, offset)) | u32::from(read_u16(bytes, offset + 2)) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(bytes, offset)) | u64::from(read_u32(bytes, offset + 4)) << 32
+}
+
+// All bytes of a table, including its checksum byte, add up to 0
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Parses the RSDP at the start of the bytes. Returns `None` if the signature or a
+/// checksum doesn't match.
+pub fn parse_rsdp(bytes: &[u8]) -> Option<Rsdp> {
+    if bytes.len() < 20 || &bytes[..8] != RSDP_SIGNATURE || !checksum_ok(&bytes[..20]) {
+        return None;
+    }
+    let revision = bytes[15];
+    let rsdt_address = read_u32(bytes, 16);
+    // the extended fields have their own checksum over the whole structure
+    let xsdt_address = if revision >= 2 && bytes.len() >= 36 && checksum_ok(&bytes[..36]) {
+        Some(read_u64(bytes, 24))
+    } else {
+        None
+    };
+    Some(Rsdp { revision, rsdt_address, xsdt_address })
+}
+
+/// Searches the bytes for the RSDP, which is aligned to 16 bytes.
+pub fn find_rsdp_in(bytes: &[u8]) -> Option<Rsdp> {
+    (0..bytes.len()).step_by(16).filter_map(|offset| parse_rsdp(&bytes[offset..])).next()
+}
+
+/// Returns the signature and the length of the table whose header is at the start of
+/// the bytes.
+pub fn parse_header(bytes: &[u8]) -> Option<([u8; 4], usize)> {
+    if bytes.len() < HEADER_SIZE {
+        return None;
+    }
+    let mut signature = [0; 4];
+    signature.copy_from_slice(&bytes[..4]);
+    Some((signature, read_u32(bytes, 4) as usize))
+}
+
+// Returns the table if it is complete and its checksum matches
+fn checked_table(bytes: &[u8]) -> Option<&[u8]> {
+    let (_, length) = parse_header(bytes)?;
+    if length < HEADER_SIZE || length > bytes.len() || !checksum_ok(&bytes[..length]) {
+        return None;
+    }
+    Some(&bytes[..length])
+}
+
+/// Returns the physical addresses of the tables listed by the RSDT (`entry_size` 4)
+/// or XSDT (`entry_size` 8).
+pub fn parse_root_table(bytes: &[u8], entry_size: usize) -> Option<Vec<u64>> {
+    let table = checked_table(bytes)?;
+    let entries = table[HEADER_SIZE..].chunks(entry_size)
+        .filter(|entry| entry.len() == entry_size)
+        .map(|entry| if entry_size == 8 { read_u64(entry, 0) } else { u64::from(read_u32(entry, 0)) })
+        .collect();
+    Some(entries)
+}
+
+/// Parses the MADT. Entries of unknown types are skipped.
+pub fn parse_madt(bytes: &[u8]) -> Option<Madt> {
+    let table = checked_table(bytes)?;
+    if &table[..4] != MADT_SIGNATURE || table.len() < HEADER_SIZE + 8 {
+        return None;
+    }
+    let mut madt = Madt {
+        local_apic_address: u64::from(read_u32(table, HEADER_SIZE)),
+        has_legacy_pics: read_u32(table, HEADER_SIZE + 4) & 1 != 0,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    // the entries follow, each starting with its type and length
+    let mut offset = HEADER_SIZE + 8;
+    while offset + 2 <= table.len() {
+        let (kind, length) = (table[offset], table[offset + 1] as usize);
+        if length < 2 || offset + length > table.len() {
+            return None;
+        }
+        let entry = &table[offset..offset + length];
+        match (kind, length) {
+            (0, 8) => madt.local_apics.push(LocalApicEntry {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: read_u32(entry, 4) & 1 != 0,
+            }),
+            (1, 12) => madt.io_apics.push(IoApicEntry {
+                id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            }),
+            (2, 10) => madt.overrides.push(InterruptOverride {
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            }),
+            // 64 bit address of the local APICs
+            (5, 12) => madt.local_apic_address = read_u64(entry, 4),
+            _ => {}
+        }
+        offset += length;
+    }
+    Some(madt)
+}
+
+// Maps the physical range for the duration of the function and passes it its bytes.
+// Returns None if the range can't be mapped
+fn with_physical<R, F>(addr: u64, len: usize, f: F) -> Option<R>
+    where F: FnOnce(&[u8]) -> R
+{
+    // unsafe: firmware tables and the BIOS area are normal memory, only read here
+    let mapping = unsafe { ioremap::<u8>(PhysAddr::new(addr), len as u64, CacheMode::WriteBack) }.ok()?;
+    // unsafe: the mapping is len bytes large and lives until the end of the function
+    let bytes = unsafe { slice::from_raw_parts(mapping.virt_addr().as_ptr::<u8>(), len) };
+    Some(f(bytes))
+}
+
+/// Searches the extended BIOS data area and the BIOS ROM area for the RSDP.
+///
+/// Maps the areas with `ioremap`, so the kernel memory must be initialized.
+pub fn find_rsdp() -> Option<Rsdp> {
+    let ebda = with_physical(EBDA_POINTER, 2, |bytes| u64::from(read_u16(bytes, 0)) << 4)?;
+    let in_ebda = if ebda != 0 {
+        with_physical(ebda, EBDA_SEARCH_SIZE, find_rsdp_in).and_then(|rsdp| rsdp)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| with_physical(BIOS_AREA_START, BIOS_AREA_SIZE, find_rsdp_in).and_then(|rsdp| rsdp))
+}
+
+// Reads the header of the table at the physical address, then maps the whole table
+fn with_table<R, F>(addr: u64, f: F) -> Option<R>
+    where F: FnOnce(&[u8]) -> Option<R>
+{
+    let (_, length) = with_physical(addr, HEADER_SIZE, parse_header)??;
+    if length < HEADER_SIZE {
+        return None;
+    }
+    with_physical(addr, length, f)?
+}
+
+/// Returns the physical address of the first table with the signature listed by the
+/// root table.
+pub fn find_table(rsdp: &Rsdp, signature: &[u8; 4]) -> Option<u64> {
+    let (root_addr, entry_size) = rsdp.root_table();
+    let tables = with_table(root_addr, |bytes| parse_root_table(bytes, entry_size))?;
+    tables.into_iter().find(|&addr| {
+        with_physical(addr, HEADER_SIZE, |bytes| parse_header(bytes).map(|(found, _)| &found == signature))
+            == Some(Some(true))
+    })
+}
+
+/// Finds and parses the MADT, `None` if the firmware provides none.
+pub fn madt() -> Option<Madt> {
+    let rsdp = find_rsdp()?;
+    let addr = find_table(&rsdp, MADT_SIGNATURE)?;
+    with_table(addr, parse_madt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    // Sets the checksum byte so the bytes add up to 0
+    fn fix_checksum(bytes: &mut [u8], checksum_offset: usize) {
+        bytes[checksum_offset] = 0;
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes[checksum_offset] = 0u8.wrapping_sub(sum);
+    }
+
+    fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(signature);
+        let length = (HEADER_SIZE + body.len()) as u32;
+        bytes.extend_from_slice(&length.to_le_bytes());
+        bytes.resize(HEADER_SIZE, 0);
+        bytes.extend_from_slice(body);
+        fix_checksum(&mut bytes, 9);
+        bytes
+    }
+
+    #[test]
+    fn finds_rsdp() {
+        let mut rsdp = [0u8; 36];
+        rsdp[..8].copy_from_slice(RSDP_SIGNATURE);
+        rsdp[15] = 2;
+        rsdp[16..20].copy_from_slice(&0x7fe_1000u32.to_le_bytes());
+        rsdp[24..32].copy_from_slice(&0x7fe_2000u64.to_le_bytes());
+        fix_checksum(&mut rsdp[..20], 8);
+        fix_checksum(&mut rsdp, 32);
+
+        let mut area = [0u8; 256];
+        area[0x40..0x40 + 36].copy_from_slice(&rsdp);
+        let found = find_rsdp_in(&area).unwrap();
+        assert_eq!(found, Rsdp { revision: 2, rsdt_address: 0x7fe_1000, xsdt_address: Some(0x7fe_2000) });
+        assert_eq!(found.root_table(), (0x7fe_2000, 8));
+
+        // a broken checksum or a misaligned copy is not found
+        area[0x40 + 19] ^= 1;
+        assert_eq!(find_rsdp_in(&area), None);
+        area[0x48..0x48 + 36].copy_from_slice(&rsdp);
+        assert_eq!(find_rsdp_in(&area[..0x48]), None);
+    }
+
+    #[test]
+    fn parses_root_table() {
+        let rsdt = table(b"RSDT", &[0x00, 0x10, 0, 0, 0x00, 0x20, 0, 0]);
+        assert_eq!(parse_root_table(&rsdt, 4), Some([0x1000, 0x2000].to_vec()));
+        let mut broken = rsdt.clone();
+        broken[HEADER_SIZE] = 1;
+        assert_eq!(parse_root_table(&broken, 4), None);
+    }
+
+    #[test]
+    fn parses_madt() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        // processor 0 with APIC ID 0, enabled
+        body.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+        // IOAPIC 1 at 0xfec00000 for GSIs from 0 on
+        body.extend_from_slice(&[1, 12, 1, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]);
+        // ISA IRQ 0 is GSI 2
+        body.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        // an unknown entry type
+        body.extend_from_slice(&[0x7f, 4, 0, 0]);
+
+        let madt = parse_madt(&table(MADT_SIGNATURE, &body)).unwrap();
+        assert_eq!(madt.local_apic_address, 0xfee0_0000);
+        assert!(madt.has_legacy_pics);
+        assert_eq!(madt.local_apics, [LocalApicEntry { processor_id: 0, apic_id: 0, enabled: true }]);
+        assert_eq!(madt.io_apics, [IoApicEntry { id: 1, address: 0xfec0_0000, gsi_base: 0 }]);
+        assert_eq!(madt.overrides, [InterruptOverride { source: 0, gsi: 2, flags: 0 }]);
+
+        assert_eq!(parse_madt(&table(b"FACP", &body)), None);
+    }
+}
@@ -0,0 +1,117 @@
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageRangeInclusive, PageTableFlags, Size4KiB};
+use memory::{self, MemoryError, KERNEL_MEMORY};
+use memory::vma::{self, Backing};
+use memory::stats::PageTableAllocator;
+use self::slab::LockedSlabAllocator;
+
+pub mod slab;
+pub mod debug;
+
+// Virtual address range reserved for the kernel heap. The start address is
+// arbitrary, it only needs to be unused so it is easy to recognize in a page fault
+pub const HEAP_START: u64 = 0x_4444_4444_0000;
+pub const HEAP_SIZE: u64 = 100 * 1024;     // 100 KiB
+
+// Virtual address range the slabs of the slab caches are mapped into, a frame each.
+// The heap above only serves allocations too large for the caches
+pub const SLAB_AREA_START: u64 = 0x_4444_8888_0000;
+pub const SLAB_AREA_SIZE: u64 = 64 * 1024 * 1024;     // 64 MiB
+// Slabs mapped by init_heap, used while frames can't be taken from the kernel memory:
+// before init_kernel_memory and when the code allocating holds its lock
+pub const SPARE_SLABS: u64 = 16;
+
+// Next unmapped page of the slab area, only advanced with the kernel memory locked
+static NEXT_SLAB: AtomicUsize =
+    AtomicUsize::new((SLAB_AREA_START + SPARE_SLABS * slab::SLAB_SIZE as u64) as usize);
+
+// The heap allocator used for Box, Vec, etc. Small objects come from slab caches,
+// everything else from the linked list heap. It starts out empty and is given
+// its memory by init_heap once the heap pages are mapped.
+// Only registered in non-test mode so host unit tests keep the std allocator
+#[cfg_attr(all(not(test), not(feature = "heap_debug")), global_allocator)]
+pub static ALLOCATOR: LockedSlabAllocator = LockedSlabAllocator::empty();
+
+// With the heap_debug feature, allocations go through the debug allocator, which
+// tracks them and allocates from ALLOCATOR with redzones around them
+#[cfg(feature = "heap_debug")]
+#[cfg_attr(not(test), global_allocator)]
+static DEBUG_ALLOCATOR: debug::DebugAllocator = debug::DebugAllocator;
+
+/// Maps the kernel heap region and the spare slabs to newly allocated frames and
+/// hands them to the global allocator. Further slabs are mapped to frames of the
+/// kernel memory once `memory::init_kernel_memory` is called.
+///
+/// Must be called before any `alloc` types are used.
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MemoryError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    map_pages(pages(HEAP_START, HEAP_SIZE), flags, mapper, frame_allocator)?;
+    let spare_slabs_size = SPARE_SLABS * slab::SLAB_SIZE as u64;
+    map_pages(pages(SLAB_AREA_START, spare_slabs_size), flags, mapper, frame_allocator)?;
+
+    // register the heap and the slab area so they can't be reserved for something else
+    vma::reserve("kernel heap", VirtAddr::new(HEAP_START), HEAP_SIZE, flags, Backing::Mapped)
+        .expect("kernel heap overlaps a reserved area");
+    vma::reserve("slab area", VirtAddr::new(SLAB_AREA_START), SLAB_AREA_SIZE, flags, Backing::Mapped)
+        .expect("slab area overlaps a reserved area");
+
+    let mut allocator = ALLOCATOR.lock();
+    // unsafe: the heap range must be mapped and unused, which was ensured above.
+    // Must only be called once
+    unsafe { allocator.init(HEAP_START as usize, HEAP_SIZE as usize); }
+    for i in 0..SPARE_SLABS {
+        // unsafe: the spare slabs were mapped above and are used by nothing else
+        unsafe { allocator.add_spare_slab((SLAB_AREA_START + i * slab::SLAB_SIZE as u64) as *mut u8) };
+    }
+    allocator.set_slab_source(map_slab);
+
+    Ok(())
+}
+
+// Returns the pages of the range, the size is rounded up to whole pages
+fn pages(start: u64, size: u64) -> PageRangeInclusive {
+    let start = VirtAddr::new(start);
+    // end address is inclusive, so subtract one to avoid mapping an extra page
+    let end = start + size - 1u64;
+    Page::range_inclusive(Page::containing_address(start), Page::containing_address(end))
+}
+
+fn map_pages(
+    pages: PageRangeInclusive,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MemoryError> {
+    for page in pages {
+        // every page gets its own frame, the frame allocator may also be used by
+        // map_to to create any missing page tables
+        let frame = frame_allocator.allocate_frame()
+            .ok_or(MemoryError::FrameAllocationFailed)?;
+        // unsafe: the ranges are not used by anything else, so mapping them can't
+        // alias other memory
+        unsafe { mapper.map_to(page, frame, flags, &mut PageTableAllocator(frame_allocator))?.flush() };
+    }
+    Ok(())
+}
+
+// The slab source of the global allocator: maps the next page of the slab area to a
+// new frame. Has none before the kernel memory is set up and while its lock is held,
+// e.g. by the code allocating, the caches use the spare slabs then
+fn map_slab() -> Option<NonNull<u8>> {
+    let mut kernel_memory = KERNEL_MEMORY.try_lock()?;
+    let memory = kernel_memory.as_mut()?;
+    let addr = NEXT_SLAB.load(Ordering::Relaxed) as u64;
+    if addr >= SLAB_AREA_START + SLAB_AREA_SIZE {
+        return None;
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let page = Page::containing_address(VirtAddr::new(addr));
+    memory::map_zeroed_page(page, flags, &mut memory.page_table, &mut memory.frame_allocator).ok()?;
+    NEXT_SLAB.store((addr + slab::SLAB_SIZE as u64) as usize, Ordering::Relaxed);
+    NonNull::new(addr as *mut u8)
+}
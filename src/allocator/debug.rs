@@ -0,0 +1,441 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::fmt;
+use core::ptr;
+use core::slice;
+use spin::Mutex;
+use super::ALLOCATOR;
+use super::slab;
+
+/// Bytes before and after every allocation that must not be written to.
+pub const REDZONE_SIZE: usize = 32;
+/// Value of the redzone bytes.
+pub const REDZONE_BYTE: u8 = 0xfd;
+/// Value freed memory is overwritten with.
+pub const POISON_BYTE: u8 = 0xdd;
+/// Value new allocations are filled with, so reads of uninitialized memory stand out.
+pub const UNINIT_BYTE: u8 = 0xcd;
+/// Number of live allocations the tracker has room for. Allocations beyond are still
+/// checked, but aren't listed by `dump_allocations`.
+pub const MAX_TRACKED: usize = 512;
+/// Number of return addresses recorded per allocation.
+pub const CALLER_DEPTH: usize = 6;
+
+/// The live allocations, kept separate from the heap lock.
+pub static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+/// A live allocation as seen by its user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub addr: usize,
+    pub size: usize,
+    pub align: usize,
+    // return addresses of the callers, innermost first and 0 where the walk stopped
+    pub callers: [usize; CALLER_DEPTH],
+}
+
+/// A fixed size table of live allocations. It can't use the heap it tracks.
+pub struct Tracker {
+    allocations: [Option<Allocation>; MAX_TRACKED],
+    live: usize,
+    // live allocations that didn't fit into the table. Frees of addresses that aren't
+    // tracked count it down, so it can be off by double frees in the meantime
+    untracked: u64,
+}
+
+impl Tracker {
+    pub const fn new() -> Self {
+        Tracker {
+            allocations: [None; MAX_TRACKED],
+            live: 0,
+            untracked: 0,
+        }
+    }
+
+    pub fn insert(&mut self, allocation: Allocation) {
+        match self.allocations.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(allocation);
+                self.live += 1;
+            }
+            None => self.untracked += 1,
+        }
+    }
+
+    /// Removes the allocation at the address and returns it, `None` if it isn't tracked.
+    pub fn remove(&mut self, addr: usize) -> Option<Allocation> {
+        for slot in self.allocations.iter_mut() {
+            if slot.map(|allocation| allocation.addr) == Some(addr) {
+                self.live -= 1;
+                return slot.take();
+            }
+        }
+        None
+    }
+
+    /// Removes the allocation being freed at the address and returns it. Fails with
+    /// `DoubleFree` if no live allocation is at the address, i.e. it was freed already.
+    ///
+    /// While allocations that didn't fit into the table are live, a missing one may be
+    /// one of them, so `Ok(None)` is returned instead and it's counted as freed.
+    pub fn free(&mut self, addr: usize) -> Result<Option<Allocation>, Corruption> {
+        match self.remove(addr) {
+            Some(allocation) => Ok(Some(allocation)),
+            None if self.untracked > 0 => {
+                self.untracked -= 1;
+                Ok(None)
+            }
+            None => Err(Corruption { kind: CorruptionKind::DoubleFree, addr }),
+        }
+    }
+
+    /// Iterates over the tracked allocations in no particular order.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = Allocation> + 'a {
+        self.allocations.iter().filter_map(|slot| *slot)
+    }
+
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    pub fn untracked(&self) -> u64 {
+        self.untracked
+    }
+}
+
+/// What was found overwritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The redzone before the allocation.
+    Underflow,
+    /// The redzone after the allocation.
+    Overflow,
+    /// A freed block, found when it was allocated again.
+    WriteAfterFree,
+    /// The allocation was already freed, or never allocated.
+    DoubleFree,
+}
+
+/// Heap corruption, with the address of the first byte found overwritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    pub kind: CorruptionKind,
+    pub addr: usize,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} at {:#x}", self.kind, self.addr)
+    }
+}
+
+// Size of the padding before an allocation. It holds the redzone and keeps the
+// allocation aligned
+fn front_size(layout: &Layout) -> usize {
+    cmp::max(REDZONE_SIZE, layout.align())
+}
+
+/// Returns the layout of the block holding an allocation and its redzones, `None` if
+/// the allocation is too large to add them.
+pub fn padded_layout(layout: &Layout) -> Option<Layout> {
+    let size = layout.size().checked_add(front_size(layout) + REDZONE_SIZE)?;
+    // no allocation can be larger than isize::MAX bytes
+    if size > isize::max_value() as usize {
+        return None;
+    }
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+// Returns the padded layout of an allocation whose block was allocated with it
+unsafe fn allocated_layout(layout: &Layout) -> Layout {
+    // unsafe: padded_layout succeeded for it before
+    Layout::from_size_align_unchecked(front_size(layout) + layout.size() + REDZONE_SIZE, layout.align())
+}
+
+// Returns the address of the first byte of the range that differs from the value
+fn find_mismatch(start: *const u8, len: usize, value: u8) -> Option<usize> {
+    // unsafe: callers only pass ranges of the block they own
+    let bytes = unsafe { slice::from_raw_parts(start, len) };
+    bytes.iter().position(|&byte| byte != value).map(|offset| start as usize + offset)
+}
+
+/// Turns a block allocated with `padded_layout` into an allocation: writes the
+/// redzones and fills the allocation with `UNINIT_BYTE`. Returns the allocation.
+///
+/// Blocks of slab caches are checked to be unmodified since they were freed.
+///
+/// Unsafe: the block must be newly allocated with the padded layout.
+pub unsafe fn prepare(block: *mut u8, layout: &Layout) -> Result<*mut u8, Corruption> {
+    let padded = allocated_layout(layout);
+    if let Some(block_size) = slab::block_size(&padded) {
+        // the free list link is the only part of a free block the allocator writes to
+        let start = block.add(slab::FREE_BLOCK_HEADER);
+        if let Some(addr) = find_mismatch(start, block_size - slab::FREE_BLOCK_HEADER, POISON_BYTE) {
+            return Err(Corruption { kind: CorruptionKind::WriteAfterFree, addr });
+        }
+    }
+
+    let front = front_size(layout);
+    let ptr = block.add(front);
+    block.write_bytes(REDZONE_BYTE, front);
+    ptr.write_bytes(UNINIT_BYTE, layout.size());
+    ptr.add(layout.size()).write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+    Ok(ptr)
+}
+
+/// Returns the first corruption of the redzones of the allocation, if any.
+///
+/// Unsafe: the allocation must have been returned by `prepare` with the same layout.
+pub unsafe fn check(ptr: *mut u8, layout: &Layout) -> Option<Corruption> {
+    let front = front_size(layout);
+    if let Some(addr) = find_mismatch(ptr.sub(front), front, REDZONE_BYTE) {
+        return Some(Corruption { kind: CorruptionKind::Underflow, addr });
+    }
+    if let Some(addr) = find_mismatch(ptr.add(layout.size()), REDZONE_SIZE, REDZONE_BYTE) {
+        return Some(Corruption { kind: CorruptionKind::Overflow, addr });
+    }
+    None
+}
+
+/// Checks the redzones of the allocation and poisons it. Returns the block to free
+/// with `padded_layout`.
+///
+/// Blocks of slab caches are poisoned as a whole, so `prepare` can check all of it.
+///
+/// Unsafe: the allocation must have been returned by `prepare` with the same layout.
+pub unsafe fn release(ptr: *mut u8, layout: &Layout) -> Result<*mut u8, Corruption> {
+    if let Some(corruption) = check(ptr, layout) {
+        return Err(corruption);
+    }
+    let block = ptr.sub(front_size(layout));
+    let padded = allocated_layout(layout);
+    let size = slab::block_size(&padded).unwrap_or(padded.size());
+    block.write_bytes(POISON_BYTE, size);
+    Ok(block)
+}
+
+// Returns the return addresses of the callers by following the saved frame pointers,
+// see backtrace::Frames
+#[inline(never)]
+fn callers() -> [usize; CALLER_DEPTH] {
+    use backtrace::Frames;
+
+    let mut callers = [0; CALLER_DEPTH];
+    // the first return address is in DebugAllocator::alloc, which isn't interesting
+    for (caller, return_addr) in callers.iter_mut().zip(Frames::current().skip(1)) {
+        *caller = return_addr;
+    }
+    callers
+}
+
+// Prints the corruption and the allocation it was found in, then panics
+fn report(corruption: Corruption, allocation: Option<Allocation>) -> ! {
+    serial_println!("heap corruption kind={:?} addr={:#x}", corruption.kind, corruption.addr);
+    if let Some(allocation) = allocation {
+        serial_println!("{}", AllocationLine(&allocation));
+    }
+    panic!("heap corruption: {}", corruption);
+}
+
+// Formats an allocation as a single `key=value` line
+struct AllocationLine<'a>(&'a Allocation);
+
+impl<'a> fmt::Display for AllocationLine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let allocation = self.0;
+        write!(f, "heap allocation addr={:#x} size={} align={} callers=",
+               allocation.addr, allocation.size, allocation.align)?;
+        for (i, &caller) in allocation.callers.iter().take_while(|&&caller| caller != 0).enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{:#x}", caller)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints every live allocation with its callers over serial, one line each, followed
+/// by a summary line. Allocations with overwritten redzones are reported as well.
+///
+/// Takes the tracker lock, so it must not be called from an interrupt handler.
+pub fn dump_allocations() {
+    let tracker = TRACKER.lock();
+    let mut bytes = 0;
+    for allocation in tracker.iter() {
+        bytes += allocation.size;
+        serial_println!("{}", AllocationLine(&allocation));
+        let layout = Layout::from_size_align(allocation.size, allocation.align)
+            .expect("tracked allocation with invalid layout");
+        // unsafe: the allocation is live and was prepared with its layout
+        if let Some(corruption) = unsafe { check(allocation.addr as *mut u8, &layout) } {
+            serial_println!("heap corruption kind={:?} addr={:#x}", corruption.kind, corruption.addr);
+        }
+    }
+    serial_println!("heap allocations live={} bytes={} untracked={}",
+                    tracker.live(), bytes, tracker.untracked());
+}
+
+/// Global allocator around `ALLOCATOR` which records every allocation and surrounds
+/// it with redzones. Freed memory is poisoned. Corruption found on allocation or
+/// deallocation is reported over serial and panics.
+///
+/// Used instead of `ALLOCATOR` when the kernel is built with the `heap_debug`
+/// feature. Allocations take more heap memory and time.
+pub struct DebugAllocator;
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let callers = callers();
+        // too large allocations fail like any other, the allocation error handler reports them
+        let padded = match padded_layout(&layout) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let block = ALLOCATOR.lock().allocate(padded);
+        if block.is_null() {
+            return block;
+        }
+        let ptr = prepare(block, &layout).unwrap_or_else(|corruption| report(corruption, None));
+        TRACKER.lock().insert(Allocation { addr: ptr as usize, size: layout.size(), align: layout.align(), callers });
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // the lock is released before reporting, the panic handler may dump the tracker
+        let freed = TRACKER.lock().free(ptr as usize);
+        // a freed allocation must not be checked, its block may be in use again
+        let allocation = freed.unwrap_or_else(|corruption| report(corruption, None));
+        match release(ptr, &layout) {
+            Ok(block) => ALLOCATOR.lock().deallocate(block, allocated_layout(&layout)),
+            Err(corruption) => report(corruption, allocation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::boxed::Box;
+    use allocator::slab::SlabAllocator;
+    use core::ptr::NonNull;
+
+    const HEAP_SIZE: usize = 64 * 1024;
+
+    // Slabs from the host's allocator, they are leaked
+    fn host_slab() -> Option<NonNull<u8>> {
+        let layout = Layout::from_size_align(slab::SLAB_SIZE, slab::SLAB_SIZE).unwrap();
+        NonNull::new(unsafe { std::alloc::alloc(layout) })
+    }
+
+    fn heap() -> SlabAllocator {
+        let heap: &'static mut [u8; HEAP_SIZE] = Box::leak(Box::new([0; HEAP_SIZE]));
+        let mut allocator = SlabAllocator::empty();
+        unsafe { allocator.init(heap.as_mut_ptr() as usize, HEAP_SIZE) };
+        allocator.set_slab_source(host_slab);
+        allocator
+    }
+
+    fn allocate(heap: &mut SlabAllocator, layout: &Layout) -> *mut u8 {
+        let block = heap.allocate(padded_layout(layout).unwrap());
+        assert!(!block.is_null());
+        unsafe { prepare(block, layout) }.expect("fresh block is corrupted")
+    }
+
+    fn allocation(addr: usize) -> Allocation {
+        Allocation { addr, size: 8, align: 8, callers: [0; CALLER_DEPTH] }
+    }
+
+    #[test]
+    fn tracks_live_allocations() {
+        let mut tracker = Tracker::new();
+        tracker.insert(allocation(0x1000));
+        tracker.insert(allocation(0x2000));
+        assert_eq!(tracker.remove(0x1000), Some(allocation(0x1000)));
+        assert_eq!(tracker.remove(0x1000), None);
+        assert_eq!(tracker.live(), 1);
+        assert_eq!(tracker.iter().next(), Some(allocation(0x2000)));
+
+        for i in 0..MAX_TRACKED {
+            tracker.insert(allocation(0x10000 + i * 16));
+        }
+        assert_eq!((tracker.live(), tracker.untracked()), (MAX_TRACKED, 1));
+    }
+
+    #[test]
+    fn detects_double_free() {
+        let mut tracker = Tracker::new();
+        tracker.insert(allocation(0x1000));
+        assert_eq!(tracker.free(0x1000), Ok(Some(allocation(0x1000))));
+        assert_eq!(tracker.free(0x1000), Err(Corruption { kind: CorruptionKind::DoubleFree, addr: 0x1000 }));
+
+        // with untracked allocations, a missing one may be live
+        for i in 0..MAX_TRACKED + 1 {
+            tracker.insert(allocation(0x10000 + i * 16));
+        }
+        assert_eq!(tracker.free(0x1000), Ok(None));
+
+        // once the untracked allocation is freed, double frees are found again
+        assert_eq!(tracker.untracked(), 0);
+        assert_eq!(tracker.free(0x10000), Ok(Some(allocation(0x10000))));
+        assert_eq!(tracker.free(0x10000), Err(Corruption { kind: CorruptionKind::DoubleFree, addr: 0x10000 }));
+    }
+
+    #[test]
+    fn rejects_layouts_too_large_for_redzones() {
+        let layout = Layout::from_size_align(isize::max_value() as usize - 40, 8).unwrap();
+        assert_eq!(padded_layout(&layout), None);
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        assert_eq!(padded_layout(&layout).map(|padded| padded.size()), Some(64 + 100 + REDZONE_SIZE));
+    }
+
+    #[test]
+    fn allocations_are_aligned_and_surrounded_by_redzones() {
+        let mut heap = heap();
+        for &(size, align) in [(1, 1), (24, 8), (100, 64), (3000, 16)].iter() {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptr = allocate(&mut heap, &layout);
+            assert_eq!(ptr as usize % align, 0);
+            unsafe {
+                assert_eq!(*ptr.sub(1), REDZONE_BYTE);
+                assert_eq!(*ptr, UNINIT_BYTE);
+                assert_eq!(*ptr.add(size), REDZONE_BYTE);
+                assert_eq!(check(ptr, &layout), None);
+            }
+        }
+    }
+
+    #[test]
+    fn detects_overflow_and_underflow() {
+        let mut heap = heap();
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let ptr = allocate(&mut heap, &layout);
+        unsafe {
+            *ptr.add(40) = 0;
+            let corruption = release(ptr, &layout).unwrap_err();
+            assert_eq!(corruption, Corruption { kind: CorruptionKind::Overflow, addr: ptr as usize + 40 });
+            *ptr.add(40) = REDZONE_BYTE;
+
+            *ptr.sub(3) = 0;
+            assert_eq!(check(ptr, &layout).map(|corruption| corruption.kind), Some(CorruptionKind::Underflow));
+        }
+    }
+
+    #[test]
+    fn poisons_freed_memory() {
+        let mut heap = heap();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = allocate(&mut heap, &layout);
+        unsafe {
+            let block = release(ptr, &layout).unwrap();
+            assert!((0..100).all(|offset| *ptr.add(offset) == POISON_BYTE));
+            heap.deallocate(block, padded_layout(&layout).unwrap());
+
+            // a write after free is found once the block is handed out again
+            *ptr.add(10) = 1;
+            let block = heap.allocate(padded_layout(&layout).unwrap());
+            let corruption = prepare(block, &layout).unwrap_err();
+            assert_eq!(corruption, Corruption { kind: CorruptionKind::WriteAfterFree, addr: ptr as usize + 10 });
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::mem;
+use core::ptr::{self, NonNull};
+use spin::{Mutex, MutexGuard};
+use linked_list_allocator::Heap;
+
+/// Block sizes of the slab caches. Larger allocations are served by the fallback heap.
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// Size of a slab: the memory a cache takes from the slab source whenever it runs out
+/// of free blocks. Slabs are aligned to their size, so every block is aligned to its size.
+pub const SLAB_SIZE: usize = 4096;
+
+/// Returns a new unused slab of `SLAB_SIZE` bytes, aligned to its size, or `None` if
+/// there is none. Called with the allocator locked, so it must not allocate.
+pub type SlabSource = fn() -> Option<NonNull<u8>>;
+
+// Header written into every free block, linking it to the next free block of the cache
+struct FreeBlock {
+    next: Option<&'static mut FreeBlock>,
+}
+
+/// Bytes at the start of a free block the allocator uses itself.
+pub(crate) const FREE_BLOCK_HEADER: usize = mem::size_of::<FreeBlock>();
+
+/// Statistics of a single slab cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub block_size: usize,
+    // slabs taken from the slab source or the spare slabs, they are never returned
+    pub slabs: usize,
+    pub used_blocks: usize,
+    pub free_blocks: usize,
+    // allocations since boot, including failed ones
+    pub allocations: u64,
+    pub failed_allocations: u64,
+}
+
+/// Statistics of the allocations served by the fallback heap directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FallbackStats {
+    pub used_allocations: usize,
+    pub used_bytes: usize,
+    pub allocations: u64,
+    pub failed_allocations: u64,
+}
+
+/// A cache of equally sized blocks, kept in a free list.
+pub struct SlabCache {
+    free_list: Option<&'static mut FreeBlock>,
+    stats: CacheStats,
+}
+
+impl SlabCache {
+    const fn new(block_size: usize) -> Self {
+        SlabCache {
+            free_list: None,
+            stats: CacheStats {
+                block_size,
+                slabs: 0,
+                used_blocks: 0,
+                free_blocks: 0,
+                allocations: 0,
+                failed_allocations: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        self.free_list.take().map(|block| {
+            self.free_list = block.next.take();
+            self.stats.free_blocks -= 1;
+            self.stats.used_blocks += 1;
+            block as *mut FreeBlock as *mut u8
+        })
+    }
+
+    // Unsafe: the block must be unused, belong to this cache and be aligned to the block size
+    unsafe fn push(&mut self, ptr: *mut u8) {
+        let block = ptr as *mut FreeBlock;
+        block.write(FreeBlock { next: self.free_list.take() });
+        self.free_list = Some(&mut *block);
+        self.stats.free_blocks += 1;
+        self.stats.used_blocks -= 1;
+    }
+
+    // Splits a new slab into blocks and puts them into the free list.
+    // Unsafe: the slab must be unused and SLAB_SIZE bytes large
+    unsafe fn add_slab(&mut self, slab: *mut u8) {
+        // the debug allocator checks that free blocks stay poisoned
+        if cfg!(any(test, feature = "heap_debug")) {
+            slab.write_bytes(super::debug::POISON_BYTE, SLAB_SIZE);
+        }
+
+        let block_size = self.stats.block_size;
+        for i in 0..SLAB_SIZE / block_size {
+            // push counts every block as returned, so count it as used before
+            self.stats.used_blocks += 1;
+            self.push(slab.add(i * block_size));
+        }
+        self.stats.slabs += 1;
+    }
+}
+
+/// Allocator serving small allocations from per size class slab caches and larger
+/// ones from a linked list heap.
+///
+/// The slabs come from the slab source, e.g. frames of the frame allocator, and from
+/// spare slabs handed over up front for when the source has none. Small objects of
+/// equal size share slabs, so freeing and reallocating them doesn't fragment the heap,
+/// and they don't compete with large allocations for the heap's memory.
+pub struct SlabAllocator {
+    caches: [SlabCache; 9],
+    slab_source: Option<SlabSource>,
+    // unused slabs, linked through their first bytes like free blocks
+    spare_slabs: Option<&'static mut FreeBlock>,
+    fallback: Heap,
+    fallback_stats: FallbackStats,
+}
+
+impl SlabAllocator {
+    /// Creates an allocator without memory, `init` must be called before allocating.
+    pub const fn empty() -> Self {
+        SlabAllocator {
+            caches: [
+                SlabCache::new(SIZE_CLASSES[0]), SlabCache::new(SIZE_CLASSES[1]),
+                SlabCache::new(SIZE_CLASSES[2]), SlabCache::new(SIZE_CLASSES[3]),
+                SlabCache::new(SIZE_CLASSES[4]), SlabCache::new(SIZE_CLASSES[5]),
+                SlabCache::new(SIZE_CLASSES[6]), SlabCache::new(SIZE_CLASSES[7]),
+                SlabCache::new(SIZE_CLASSES[8]),
+            ],
+            slab_source: None,
+            spare_slabs: None,
+            fallback: Heap::empty(),
+            fallback_stats: FallbackStats {
+                used_allocations: 0,
+                used_bytes: 0,
+                allocations: 0,
+                failed_allocations: 0,
+            },
+        }
+    }
+
+    /// Hands the memory range to the fallback heap, which serves the allocations too
+    /// large for the slab caches.
+    ///
+    /// Unsafe: the range must be mapped and unused. Must only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback.init(heap_start, heap_size);
+    }
+
+    /// Sets the function the caches get new slabs from.
+    pub fn set_slab_source(&mut self, source: SlabSource) {
+        self.slab_source = Some(source);
+    }
+
+    /// Adds a slab used when the slab source has none.
+    ///
+    /// Unsafe: the slab must be mapped, unused, `SLAB_SIZE` bytes large and aligned to
+    /// its size.
+    pub unsafe fn add_spare_slab(&mut self, slab: *mut u8) {
+        let slab = slab as *mut FreeBlock;
+        slab.write(FreeBlock { next: self.spare_slabs.take() });
+        self.spare_slabs = Some(&mut *slab);
+    }
+
+    /// Returns a block fitting the layout, or null if no memory is left.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match size_class(&layout) {
+            Some(index) => {
+                let block = match self.caches[index].pop() {
+                    Some(block) => Some(block),
+                    None => self.grow_cache(index),
+                };
+                let stats = &mut self.caches[index].stats;
+                stats.allocations += 1;
+                block.unwrap_or_else(|| {
+                    stats.failed_allocations += 1;
+                    ptr::null_mut()
+                })
+            }
+            None => {
+                self.fallback_stats.allocations += 1;
+                match self.fallback.allocate_first_fit(layout) {
+                    Ok(ptr) => {
+                        self.fallback_stats.used_allocations += 1;
+                        self.fallback_stats.used_bytes += layout.size();
+                        ptr.as_ptr()
+                    }
+                    Err(_) => {
+                        self.fallback_stats.failed_allocations += 1;
+                        ptr::null_mut()
+                    }
+                }
+            }
+        }
+    }
+
+    /// Frees a block returned by `allocate` with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(index) => self.caches[index].push(ptr),
+            None => {
+                self.fallback.deallocate(NonNull::new_unchecked(ptr), layout);
+                self.fallback_stats.used_allocations -= 1;
+                self.fallback_stats.used_bytes -= layout.size();
+            }
+        }
+    }
+
+    /// Iterates over the statistics of all caches, from the smallest size class up.
+    pub fn cache_stats<'a>(&'a self) -> impl Iterator<Item = CacheStats> + 'a {
+        self.caches.iter().map(|cache| cache.stats())
+    }
+
+    pub fn fallback_stats(&self) -> FallbackStats {
+        self.fallback_stats
+    }
+
+    // Takes a new slab from the slab source, or a spare one, and returns its first block
+    fn grow_cache(&mut self, index: usize) -> Option<*mut u8> {
+        let slab = match self.slab_source.and_then(|source| source()) {
+            Some(slab) => slab.as_ptr(),
+            None => self.take_spare_slab()?,
+        };
+        // unsafe: the slab is unused and handed to this cache only
+        unsafe { self.caches[index].add_slab(slab) };
+        self.caches[index].pop()
+    }
+
+    fn take_spare_slab(&mut self) -> Option<*mut u8> {
+        self.spare_slabs.take().map(|slab| {
+            self.spare_slabs = slab.next.take();
+            slab as *mut FreeBlock as *mut u8
+        })
+    }
+}
+
+// Returns the index of the smallest size class fitting the layout. Blocks are aligned
+// to their size, so the alignment must fit as well
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = cmp::max(cmp::max(layout.size(), layout.align()), mem::size_of::<FreeBlock>());
+    SIZE_CLASSES.iter().position(|&block_size| block_size >= size)
+}
+
+/// Returns the block size of the slab cache serving the layout, `None` if it is served
+/// by the fallback heap.
+pub(crate) fn block_size(layout: &Layout) -> Option<usize> {
+    size_class(layout).map(|index| SIZE_CLASSES[index])
+}
+
+/// A SlabAllocator behind a spin lock, usable as global allocator.
+pub struct LockedSlabAllocator(Mutex<SlabAllocator>);
+
+impl LockedSlabAllocator {
+    pub const fn empty() -> Self {
+        LockedSlabAllocator(Mutex::new(SlabAllocator::empty()))
+    }
+
+    pub fn lock<'a>(&'a self) -> MutexGuard<'a, SlabAllocator> {
+        self.0.lock()
+    }
+
+    pub fn try_lock<'a>(&'a self) -> Option<MutexGuard<'a, SlabAllocator>> {
+        self.0.try_lock()
+    }
+}
+
+unsafe impl GlobalAlloc for LockedSlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().deallocate(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const HEAP_SIZE: usize = 512 * 1024;
+
+    // Slabs from the host's allocator, they are leaked
+    fn host_slab() -> Option<NonNull<u8>> {
+        let layout = Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap();
+        NonNull::new(unsafe { std::alloc::alloc(layout) })
+    }
+
+    fn no_slab() -> Option<NonNull<u8>> {
+        None
+    }
+
+    fn allocator() -> SlabAllocator {
+        let heap: &'static mut [u8; HEAP_SIZE] = Box::leak(Box::new([0; HEAP_SIZE]));
+        let mut allocator = SlabAllocator::empty();
+        unsafe { allocator.init(heap.as_mut_ptr() as usize, HEAP_SIZE) };
+        allocator.set_slab_source(host_slab);
+        allocator
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    // Small deterministic pseudo random number generator (xorshift)
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 as usize
+        }
+    }
+
+    #[test]
+    fn picks_size_classes() {
+        assert_eq!(size_class(&layout(1, 1)), Some(0));
+        assert_eq!(size_class(&layout(9, 8)), Some(1));
+        assert_eq!(size_class(&layout(8, 64)), Some(3));
+        assert_eq!(size_class(&layout(2048, 8)), Some(8));
+        assert_eq!(size_class(&layout(2049, 8)), None);
+        assert_eq!(size_class(&layout(8, 4096)), None);
+    }
+
+    #[test]
+    fn blocks_are_aligned_and_distinct() {
+        let mut allocator = allocator();
+        let mut blocks = Vec::new();
+        for _ in 0..100 {
+            let block = allocator.allocate(layout(24, 8));
+            assert!(!block.is_null());
+            assert_eq!(block as usize % 32, 0);
+            blocks.push(block as usize);
+        }
+        blocks.sort();
+        assert!(blocks.windows(2).all(|pair| pair[1] - pair[0] >= 32));
+
+        let stats = allocator.cache_stats().nth(2).unwrap();
+        assert_eq!(stats.block_size, 32);
+        assert_eq!(stats.used_blocks, 100);
+        assert_eq!(stats.slabs, 1);
+        assert_eq!(stats.free_blocks, SLAB_SIZE / 32 - 100);
+    }
+
+    #[test]
+    fn reuses_freed_blocks() {
+        let mut allocator = allocator();
+        let first = allocator.allocate(layout(64, 8));
+        unsafe { allocator.deallocate(first, layout(64, 8)) };
+        assert_eq!(allocator.allocate(layout(64, 8)), first);
+        assert_eq!(allocator.cache_stats().nth(3).unwrap().allocations, 2);
+    }
+
+    #[test]
+    fn large_allocations_use_fallback() {
+        let mut allocator = allocator();
+        let block = allocator.allocate(layout(10000, 16));
+        assert!(!block.is_null());
+        let stats = allocator.fallback_stats();
+        assert_eq!((stats.used_allocations, stats.used_bytes), (1, 10000));
+        assert!(allocator.cache_stats().all(|stats| stats.slabs == 0));
+
+        unsafe { allocator.deallocate(block, layout(10000, 16)) };
+        assert_eq!(allocator.fallback_stats().used_bytes, 0);
+    }
+
+    #[test]
+    fn exhaustion_returns_null() {
+        let mut allocator = SlabAllocator::empty();
+        let heap: &'static mut [u8; HEAP_SIZE] = Box::leak(Box::new([0; HEAP_SIZE]));
+        unsafe { allocator.init(heap.as_mut_ptr() as usize, HEAP_SIZE) };
+        allocator.set_slab_source(no_slab);
+        // two spare slabs of two blocks each
+        for _ in 0..2 {
+            unsafe { allocator.add_spare_slab(host_slab().unwrap().as_ptr()) };
+        }
+
+        let mut count = 0;
+        while !allocator.allocate(layout(2048, 8)).is_null() {
+            count += 1;
+        }
+        assert_eq!(count, 4);
+        let stats = allocator.cache_stats().last().unwrap();
+        assert_eq!((stats.slabs, stats.failed_allocations), (2, 1));
+        // the heap isn't used for slabs, so it still serves large allocations
+        assert!(!allocator.allocate(layout(16 * 1024, 8)).is_null());
+        assert!(allocator.allocate(layout(HEAP_SIZE, 8)).is_null());
+    }
+
+    #[test]
+    fn slabs_come_from_the_source() {
+        let mut allocator = allocator();
+        let spare = host_slab().unwrap().as_ptr();
+        unsafe { allocator.add_spare_slab(spare) };
+        let block = allocator.allocate(layout(64, 8));
+        // neither the spare slab nor the heap, which only has large allocations
+        assert!((block as usize) < spare as usize || block as usize >= spare as usize + SLAB_SIZE);
+        assert_eq!(allocator.fallback_stats().allocations, 0);
+    }
+
+    // Allocates and frees random sizes, checking that no block is overwritten
+    fn stress(allocator: &mut SlabAllocator, seed: u64) {
+        let mut random = Random(seed);
+        let mut live: Vec<(*mut u8, Layout, u8)> = Vec::new();
+        for round in 0..5000 {
+            if live.len() < 100 && random.next() % 3 != 0 {
+                let size = match random.next() % 10 {
+                    0 => 2049 + random.next() % 4096,
+                    _ => 1 + random.next() % 2048,
+                };
+                let layout = layout(size, 1 << (random.next() % 4));
+                let block = allocator.allocate(layout);
+                assert!(!block.is_null(), "allocation of {:?} failed", layout);
+                let pattern = round as u8;
+                unsafe { ptr::write_bytes(block, pattern, size) };
+                live.push((block, layout, pattern));
+            } else if !live.is_empty() {
+                let index = random.next() % live.len();
+                let (block, layout, pattern) = live.swap_remove(index);
+                for offset in 0..layout.size() {
+                    assert_eq!(unsafe { *block.add(offset) }, pattern, "block overwritten");
+                }
+                unsafe { allocator.deallocate(block, layout) };
+            }
+        }
+        for (block, layout, _) in live {
+            unsafe { allocator.deallocate(block, layout) };
+        }
+    }
+
+    #[test]
+    fn stress_does_not_fragment() {
+        let mut allocator = allocator();
+        stress(&mut allocator, 0x2545_f491_4f6c_dd1d);
+        let slabs: Vec<usize> = allocator.cache_stats().map(|stats| stats.slabs).collect();
+        assert!(allocator.cache_stats().all(|stats| stats.used_blocks == 0));
+        assert_eq!(allocator.fallback_stats().used_allocations, 0);
+
+        // the same workload again is served entirely from the existing slabs
+        stress(&mut allocator, 0x2545_f491_4f6c_dd1d);
+        let slabs_again: Vec<usize> = allocator.cache_stats().map(|stats| stats.slabs).collect();
+        assert_eq!(slabs, slabs_again);
+    }
+}
@@ -0,0 +1,313 @@
+use alloc::vec::Vec;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::registers::model_specific::Msr;
+use acpi::{InterruptOverride, Madt};
+use memory::{ioremap, CacheMode, MemoryError};
+
+// IA32_APIC_BASE model specific register, bit 11 enables the local APIC
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers, as offsets of the register page
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TASK_PRIORITY: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SPURIOUS: u64 = 0xf0;
+// First of the 8 in-service registers, 32 vectors each at 16 byte distance
+const LAPIC_IN_SERVICE: u64 = 0x100;
+// Bit of the spurious interrupt vector register enabling the local APIC
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// IOAPIC registers are accessed indirectly: the index is written to IOREGSEL, then the
+// register is read or written through IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+/// Bits of an IOAPIC redirection entry.
+pub const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+pub const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+pub const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Number of legacy ISA IRQs.
+pub const LEGACY_IRQ_COUNT: u8 = 16;
+
+/// Vector of spurious interrupts from the local APIC. They must not be acknowledged.
+pub const SPURIOUS_INTERRUPT_ID: u8 = 0xff;
+
+// Virtual address of the local APIC registers, 0 while the PICs are used
+static LOCAL_APIC: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// IOAPICs found in the MADT, set up by `init`.
+    pub static ref IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+}
+// Global system interrupt of each legacy IRQ, None if no IOAPIC input is connected
+static LEGACY_GSIS: Mutex<[Option<u32>; LEGACY_IRQ_COUNT as usize]> =
+    Mutex::new([None; LEGACY_IRQ_COUNT as usize]);
+
+/// Returns `true` if the CPU has a local APIC, as reported by CPUID.
+pub fn is_supported() -> bool {
+    // unsafe: CPUID leaf 1 exists on every x86_64 CPU
+    unsafe { core::arch::x86_64::__cpuid(1) }.edx & (1 << 9) != 0
+}
+
+/// Returns `true` once interrupts are delivered through the APICs.
+pub fn is_active() -> bool {
+    LOCAL_APIC.load(Ordering::Acquire) != 0
+}
+
+// Unsafe: the local APIC must be mapped
+unsafe fn lapic_read(register: u64) -> u32 {
+    ptr::read_volatile((LOCAL_APIC.load(Ordering::Acquire) as u64 + register) as *const u32)
+}
+
+// Unsafe: the local APIC must be mapped, and the value valid for the register
+unsafe fn lapic_write(register: u64, value: u32) {
+    ptr::write_volatile((LOCAL_APIC.load(Ordering::Acquire) as u64 + register) as *mut u32, value)
+}
+
+/// Returns the ID of the local APIC of the executing processor.
+pub fn local_apic_id() -> Option<u8> {
+    if !is_active() {
+        return None;
+    }
+    // unsafe: the local APIC is mapped while it is active
+    Some((unsafe { lapic_read(LAPIC_ID) } >> 24) as u8)
+}
+
+/// Signals the end of the interrupt being handled to the local APIC.
+///
+/// Must only be called while the APICs are active.
+pub fn end_of_interrupt() {
+    debug_assert!(is_active(), "end of interrupt without local APIC");
+    // unsafe: the local APIC is mapped while it is active, any value acknowledges
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+/// Returns `true` if the local APIC delivered the interrupt with the vector and it
+/// isn't acknowledged yet. Interrupts the PICs still raise don't count.
+///
+/// Must only be called while the APICs are active.
+pub fn is_in_service(vector: u8) -> bool {
+    debug_assert!(is_active(), "in-service register without local APIC");
+    let register = LAPIC_IN_SERVICE + u64::from(vector / 32) * 0x10;
+    // unsafe: the local APIC is mapped while it is active, reading has no side effects
+    let in_service = unsafe { lapic_read(register) };
+    in_service & (1 << (vector % 32)) != 0
+}
+
+/// An IOAPIC, routing global system interrupts from `gsi_base` on to interrupt vectors.
+pub struct IoApic {
+    // virtual address of its registers
+    base: u64,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    /// Maps the registers of the IOAPIC at the physical address.
+    ///
+    /// Unsafe: there must be an IOAPIC at the address.
+    pub unsafe fn new(address: PhysAddr, gsi_base: u32) -> Result<IoApic, MemoryError> {
+        let registers = ioremap::<[u32; 8]>(address, 0x20, CacheMode::Uncached)?;
+        let base = registers.virt_addr().as_u64();
+        // the IOAPIC is never removed, so the mapping is kept
+        mem::forget(registers);
+        let mut io_apic = IoApic { base, gsi_base, entries: 0 };
+        // the version register holds the index of the last redirection entry
+        io_apic.entries = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xff) + 1;
+        Ok(io_apic)
+    }
+
+    /// Returns `true` if the IOAPIC serves the global system interrupt.
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+
+    pub fn entries(&self) -> u32 {
+        self.entries
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        // unsafe: the registers are mapped for the lifetime of the IOAPIC
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            ptr::read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        // unsafe: the registers are mapped for the lifetime of the IOAPIC
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+            ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    /// Returns the redirection entry of the global system interrupt.
+    pub fn redirection(&mut self, gsi: u32) -> u64 {
+        assert!(self.handles(gsi), "GSI {} is not served by this IOAPIC", gsi);
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+
+    /// Replaces the redirection entry of the global system interrupt.
+    pub fn set_redirection(&mut self, gsi: u32, entry: u64) {
+        assert!(self.handles(gsi), "GSI {} is not served by this IOAPIC", gsi);
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // masked while the entry is half written
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    /// Masks all redirection entries.
+    pub fn mask_all(&mut self) {
+        for index in 0..self.entries {
+            let gsi = self.gsi_base + index;
+            self.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+    }
+}
+
+/// Returns the global system interrupt a legacy ISA IRQ is connected to and the
+/// polarity and trigger bits of its redirection entry. Returns `None` if the GSI the
+/// IRQ would be identity mapped to is taken by another IRQ, e.g. IRQ 2 if the timer is
+/// connected to GSI 2.
+///
+/// ISA interrupts are active high and edge triggered, unless an override says
+/// otherwise.
+pub fn legacy_irq_route(irq: u8, overrides: &[InterruptOverride]) -> Option<(u32, u64)> {
+    Some(match overrides.iter().find(|o| o.source == irq) {
+        Some(o) => {
+            let mut bits = 0;
+            // 0b11 is active low and level triggered, 0b00 the bus default
+            if o.flags & 0b11 == 0b11 {
+                bits |= REDIRECTION_ACTIVE_LOW;
+            }
+            if o.flags >> 2 & 0b11 == 0b11 {
+                bits |= REDIRECTION_LEVEL_TRIGGERED;
+            }
+            (o.gsi, bits)
+        }
+        None if overrides.iter().any(|o| o.gsi == u32::from(irq)) => return None,
+        None => (u32::from(irq), 0),
+    })
+}
+
+/// Returns a redirection entry delivering the interrupt to the vector on the local
+/// APIC with the ID, in fixed delivery and physical destination mode.
+pub fn redirection_entry(vector: u8, apic_id: u8, bits: u64) -> u64 {
+    u64::from(vector) | bits | u64::from(apic_id) << 56
+}
+
+/// Masks all interrupts of the 8259 PICs. They must have been remapped before, so
+/// spurious interrupts they still raise don't look like exceptions.
+pub fn mask_pics() {
+    use x86_64::instructions::port::Port;
+
+    // unsafe: writing the data ports of initialized PICs sets their interrupt masks
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+/// Enables the local APIC and the IOAPICs described by the MADT and masks the PICs.
+/// The legacy IRQs are routed to the vectors from `vector_base` on, masked like all
+/// other IOAPIC inputs. `set_irq_masked` unmasks them.
+///
+/// Must be called with interrupts disabled and the PICs remapped.
+pub fn init(madt: &Madt, vector_base: u8) -> Result<(), MemoryError> {
+    let mut io_apics = Vec::new();
+    for entry in madt.io_apics.iter() {
+        // unsafe: the firmware reports an IOAPIC at the address
+        let mut io_apic = unsafe { IoApic::new(PhysAddr::new(u64::from(entry.address)), entry.gsi_base)? };
+        io_apic.mask_all();
+        io_apics.push(io_apic);
+    }
+
+    // unsafe: the firmware reports the local APIC at the address
+    let registers = unsafe {
+        ioremap::<[u32; 1024]>(PhysAddr::new(madt.local_apic_address), 4096, CacheMode::Uncached)?
+    };
+    if madt.has_legacy_pics {
+        mask_pics();
+    }
+    // unsafe: enabling the local APIC at the address it already has in the MSR
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+    }
+    LOCAL_APIC.store(registers.virt_addr().as_u64() as usize, Ordering::Release);
+    // the local APIC is never removed, so the mapping is kept
+    mem::forget(registers);
+    // unsafe: the local APIC is mapped. Accepting all priorities and enabling the APIC
+    // with the spurious vector doesn't deliver any interrupt by itself
+    unsafe {
+        lapic_write(LAPIC_TASK_PRIORITY, 0);
+        lapic_write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_INTERRUPT_ID));
+    }
+
+    // all interrupts go to the processor running this, the only one for now
+    let apic_id = local_apic_id().expect("local APIC not active");
+    let mut legacy_gsis = LEGACY_GSIS.lock();
+    for irq in 0..LEGACY_IRQ_COUNT {
+        let (gsi, bits) = match legacy_irq_route(irq, &madt.overrides) {
+            Some(route) => route,
+            None => continue,
+        };
+        if let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+            let entry = redirection_entry(vector_base + irq, apic_id, bits | REDIRECTION_MASKED);
+            io_apic.set_redirection(gsi, entry);
+            legacy_gsis[irq as usize] = Some(gsi);
+        }
+    }
+    *IO_APICS.lock() = io_apics;
+    Ok(())
+}
+
+/// Masks or unmasks the legacy IRQ at its IOAPIC. Does nothing if the IRQ isn't
+/// connected to any IOAPIC.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let gsi = match LEGACY_GSIS.lock().get(irq as usize).and_then(|gsi| *gsi) {
+        Some(gsi) => gsi,
+        None => return,
+    };
+    let mut io_apics = IO_APICS.lock();
+    if let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        let entry = io_apic.redirection(gsi);
+        io_apic.set_redirection(gsi, if masked { entry | REDIRECTION_MASKED } else { entry & !REDIRECTION_MASKED });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn routes_legacy_irqs() {
+        let overrides = [
+            InterruptOverride { source: 0, gsi: 2, flags: 0 },
+            InterruptOverride { source: 9, gsi: 9, flags: 0b1111 },
+        ];
+        assert_eq!(legacy_irq_route(0, &overrides), Some((2, 0)));
+        assert_eq!(legacy_irq_route(1, &overrides), Some((1, 0)));
+        assert_eq!(legacy_irq_route(2, &overrides), None);
+        assert_eq!(legacy_irq_route(9, &overrides),
+                   Some((9, REDIRECTION_ACTIVE_LOW | REDIRECTION_LEVEL_TRIGGERED)));
+    }
+
+    #[test]
+    fn encodes_redirection_entries() {
+        assert_eq!(redirection_entry(33, 0, 0), 33);
+        assert_eq!(redirection_entry(36, 3, REDIRECTION_LEVEL_TRIGGERED), 0x0300_0000_0000_8024);
+    }
+}
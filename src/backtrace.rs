@@ -0,0 +1,311 @@
+use core::fmt;
+use core::ptr;
+use core::str;
+use x86_64::VirtAddr;
+
+/// Size of the embedded symbol table in bytes, see build.rs.
+pub const SYMBOL_TABLE_SIZE: usize = 1 << 20;
+/// Number of frames printed at most, deeper stacks are cut off.
+pub const MAX_FRAMES: usize = 32;
+
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 20;
+
+// Generated by build.rs from the symbols of a previous build of the kernel, or empty
+static SYMBOL_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/symbols.bin"));
+
+/// The function containing an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    /// Address of the first instruction of the function.
+    pub addr: usize,
+    pub size: usize,
+}
+
+/// A table of functions sorted by address, as generated by build.rs: a u32 count,
+/// entries of address (u64), size, name offset and name length (u32 each), then the
+/// names. Integers are little endian.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    data: &'a [u8],
+    count: usize,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Returns the table in `data`, or `None` if the entries don't fit.
+    pub fn new(data: &'a [u8]) -> Option<SymbolTable<'a>> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+        let count = read_u32(data, 0) as usize;
+        if HEADER_SIZE + count * ENTRY_SIZE > data.len() {
+            return None;
+        }
+        Some(SymbolTable { data, count })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the function containing the address.
+    pub fn lookup(&self, addr: usize) -> Option<Symbol<'a>> {
+        // index of the first entry starting above the address
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.entry_addr(middle) <= addr {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        if low == 0 {
+            return None;
+        }
+        let symbol = self.symbol(low - 1)?;
+        if addr < symbol.addr + symbol.size {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+
+    fn entry_addr(&self, index: usize) -> usize {
+        read_u64(self.data, HEADER_SIZE + index * ENTRY_SIZE) as usize
+    }
+
+    // Reads the entry, `None` if its name is out of bounds or not UTF-8
+    fn symbol(&self, index: usize) -> Option<Symbol<'a>> {
+        let entry = HEADER_SIZE + index * ENTRY_SIZE;
+        let name_offset = read_u32(self.data, entry + 12) as usize;
+        let name_len = read_u32(self.data, entry + 16) as usize;
+        let name = self.data.get(name_offset..name_offset + name_len)?;
+        Some(Symbol {
+            name: str::from_utf8(name).ok()?,
+            addr: self.entry_addr(index),
+            size: read_u32(self.data, entry + 8) as usize,
+        })
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    data[offset..offset + 4].iter().rev().fold(0, |value, &byte| value << 8 | u32::from(byte))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    data[offset..offset + 8].iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
+
+/// Returns the embedded symbol table, `None` if it is empty or doesn't describe this
+/// binary: it is generated for the kernel, the test binaries link the code elsewhere.
+pub fn symbol_table() -> Option<SymbolTable<'static>> {
+    // read volatile, otherwise the compiler could fold lookups in the empty table of
+    // the first build and lay out the code differently than in the second one
+    // unsafe: reads a static
+    let data = unsafe { ptr::read_volatile(&SYMBOL_DATA) };
+    let table = SymbolTable::new(data).filter(|table| !table.is_empty())?;
+    // the table must place this very function
+    let this = symbol_table as fn() -> Option<SymbolTable<'static>> as usize;
+    match table.lookup(this) {
+        Some(symbol) if symbol.addr == this && symbol.name.ends_with("backtrace::symbol_table") => Some(table),
+        _ => None,
+    }
+}
+
+/// Iterates over the return addresses on the stack by following the saved frame
+/// pointers, innermost first. The kernel is built with frame pointers, see
+/// `eliminate-frame-pointer` in the target specification.
+///
+/// The walk stops at a frame pointer of 0, one that isn't mapped or doesn't point
+/// further up the stack, and after `MAX_FRAMES` frames.
+pub struct Frames {
+    frame: usize,
+    remaining: usize,
+}
+
+impl Frames {
+    /// Walks the stack from the frame of the calling function.
+    #[inline(always)]
+    pub fn current() -> Frames {
+        let frame: usize;
+        // unsafe: only reads the frame pointer register
+        unsafe { asm!("mov %rbp, $0" : "=r"(frame)) };
+        // unsafe: the register holds the frame pointer of the caller
+        unsafe { Frames::new(frame) }
+    }
+
+    /// Walks the stack from the frame at `frame`, e.g. the saved rbp of interrupted
+    /// code.
+    ///
+    /// Unsafe: the frame pointer must be 0 or point to a saved frame pointer followed
+    /// by a return address. Unmapped frames end the walk, which needs the recursive
+    /// page table mapping.
+    pub unsafe fn new(frame: usize) -> Frames {
+        Frames { frame, remaining: MAX_FRAMES }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        use memory::page_fault::PageWalk;
+
+        let frame = self.frame;
+        if self.remaining == 0 || frame == 0 || frame % 8 != 0 {
+            return None;
+        }
+        // the saved rbp is at the end of a page if the frame is only 8 byte aligned, so
+        // the return address may be in the next one
+        let is_mapped = |addr: usize| match VirtAddr::try_new(addr as u64) {
+            // unsafe: the recursive mapping is set up before anything runs
+            Ok(addr) => unsafe { PageWalk::new(addr) }.is_mapped(),
+            Err(_) => false,
+        };
+        if !is_mapped(frame) || !frame.checked_add(8).map_or(false, is_mapped) {
+            return None;
+        }
+        // unsafe: checked for being mapped above
+        let (next, return_addr) = unsafe { (*(frame as *const usize), *((frame + 8) as *const usize)) };
+        if return_addr == 0 {
+            return None;
+        }
+        // the stack grows down, so the caller's frame must be above. Anything else is
+        // the end of the chain
+        self.frame = if next > frame { next } else { 0 };
+        self.remaining -= 1;
+        Some(return_addr)
+    }
+}
+
+/// An address with the function containing it, printed as "0x... name+0x1c".
+pub struct Location<'a> {
+    pub addr: usize,
+    pub symbol: Option<Symbol<'a>>,
+}
+
+impl<'a> Location<'a> {
+    /// Looks up an instruction address, e.g. the faulting one of an exception.
+    pub fn instruction(table: Option<&SymbolTable<'a>>, addr: usize) -> Location<'a> {
+        Location { addr, symbol: table.and_then(|table| table.lookup(addr)) }
+    }
+
+    /// Looks up a return address. The call is the instruction before it, which
+    /// belongs to another function if the call doesn't return.
+    pub fn return_address(table: Option<&SymbolTable<'a>>, addr: usize) -> Location<'a> {
+        Location { addr, symbol: table.and_then(|table| table.lookup(addr.wrapping_sub(1))) }
+    }
+}
+
+impl<'a> fmt::Display for Location<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.addr)?;
+        match self.symbol {
+            Some(symbol) => write!(f, " {}+{:#x}", symbol.name, self.addr - symbol.addr),
+            None => write!(f, " ?"),
+        }
+    }
+}
+
+/// Prints the backtrace of the calling function over serial.
+#[inline(never)]
+pub fn print() {
+    let table = symbol_table();
+    serial_println!("backtrace:");
+    // skips the frame of this function
+    for (i, addr) in Frames::current().skip(1).enumerate() {
+        serial_println!("  #{:<2} {}", i, Location::return_address(table.as_ref(), addr));
+    }
+}
+
+/// Prints the backtrace of interrupted code over serial: the instruction pointer, then
+/// the return addresses found from its frame pointer. Takes no lock, see
+/// `serial::force_print`.
+///
+/// Unsafe: see `Frames::new`.
+pub unsafe fn print_from(instruction_pointer: usize, frame: usize) {
+    use serial::force_print;
+
+    let table = symbol_table();
+    force_print(format_args!("backtrace:\n"));
+    force_print(format_args!("  #0  {}\n", Location::instruction(table.as_ref(), instruction_pointer)));
+    for (i, addr) in Frames::new(frame).enumerate() {
+        force_print(format_args!("  #{:<2} {}\n", i + 1, Location::return_address(table.as_ref(), addr)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::vec::Vec;
+
+    // Encodes a table the way build.rs does
+    fn encode(symbols: &[(u64, u32, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+        let mut name_offset = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+        for &(addr, size, name) in symbols {
+            data.extend_from_slice(&addr.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&(name_offset as u32).to_le_bytes());
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            name_offset += name.len();
+        }
+        for &(_, _, name) in symbols {
+            data.extend_from_slice(name.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn looks_up_symbols() {
+        let data = encode(&[(0x1000, 0x20, "rust_os::first"), (0x1020, 0x10, "rust_os::second"),
+                            (0x2000, 0x8, "rust_os::third")]);
+        let table = SymbolTable::new(&data).unwrap();
+        assert_eq!(table.len(), 3);
+
+        assert_eq!(table.lookup(0xfff), None);
+        assert_eq!(table.lookup(0x1000).unwrap().name, "rust_os::first");
+        assert_eq!(table.lookup(0x101f).unwrap().name, "rust_os::first");
+        assert_eq!(table.lookup(0x1020).unwrap().name, "rust_os::second");
+        // between functions and past the last one
+        assert_eq!(table.lookup(0x1030), None);
+        assert_eq!(table.lookup(0x2004).unwrap().name, "rust_os::third");
+        assert_eq!(table.lookup(0x2008), None);
+    }
+
+    #[test]
+    fn rejects_truncated_tables() {
+        let data = encode(&[(0x1000, 0x20, "rust_os::first")]);
+        assert!(SymbolTable::new(&data[..HEADER_SIZE + ENTRY_SIZE - 1]).is_none());
+        assert!(SymbolTable::new(&[0; 4]).unwrap().is_empty());
+        // a name out of bounds has no symbol
+        let table = SymbolTable::new(&data[..HEADER_SIZE + ENTRY_SIZE]).unwrap();
+        assert_eq!(table.lookup(0x1000), None);
+    }
+
+    #[test]
+    fn embeds_fixed_size_table() {
+        // the table of the first build must take as much space as the one of the second
+        assert_eq!(SYMBOL_DATA.len(), SYMBOL_TABLE_SIZE);
+    }
+
+    #[test]
+    fn formats_locations() {
+        let data = encode(&[(0x1000, 0x20, "rust_os::first"), (0x1020, 0x10, "rust_os::second")]);
+        let table = SymbolTable::new(&data).unwrap();
+        assert_eq!(std::format!("{}", Location::instruction(Some(&table), 0x1004)),
+                   "0x0000000000001004 rust_os::first+0x4");
+        // returning to the start of the next function: the call is in the previous one
+        assert_eq!(std::format!("{}", Location::return_address(Some(&table), 0x1020)),
+                   "0x0000000000001020 rust_os::first+0x20");
+        assert_eq!(std::format!("{}", Location::instruction(None, 0x1004)),
+                   "0x0000000000001004 ?");
+    }
+}
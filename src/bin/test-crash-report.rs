@@ -0,0 +1,66 @@
+#![feature(asm)]
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+#[macro_use]
+extern crate rust_os;
+
+use rust_os::{crash, exit_qemu, hlt_loop, serial, vga_buffer, QemuExitCode};
+use core::panic::PanicInfo;
+
+// Called by the crash report instead of exiting QEMU, checks what it printed
+fn exit_handler(code: QemuExitCode) -> ! {
+    let captured = serial::stop_capture();
+    let report = captured.as_str();
+    // unsafe: the lock is held by _start, which never resumes
+    unsafe { serial::SERIAL1.force_unlock() };
+    if code != QemuExitCode::Failed {
+        serial_println!("failed");
+        serial_println!("Crash report exits with {:?}", code);
+    } else if !report.starts_with("crash report begin\nexception=invalid_opcode\nvector=6\n")
+        || !report.contains("crash report end\n") {
+        serial_println!("failed");
+        serial_println!("Unexpected report:\n{}", report);
+    } else {
+        serial_println!("ok");
+    }
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
+
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    rust_os::gdt::init();
+    rust_os::interrupts::init_idt();
+    crash::set_exit_handler(exit_handler);
+
+    // the report must not wait for the screen and serial locks, which are held here
+    let screen = vga_buffer::WRITER.lock();
+    let serial = serial::SERIAL1.lock();
+    serial::start_capture();
+    unsafe { asm!("ud2" :::: "volatile") };
+
+    drop(serial);
+    drop(screen);
+    serial::stop_capture();
+    serial_println!("failed");
+    serial_println!("No exception occurred");
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial::stop_capture();
+    // the serial lock may be held by _start
+    serial::force_print(format_args!("failed\n{}\n", info));
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
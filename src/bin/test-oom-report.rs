@@ -0,0 +1,57 @@
+#![feature(alloc)]
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+#[macro_use]
+extern crate rust_os;
+extern crate bootloader;
+extern crate alloc;
+
+use rust_os::{allocator, exit_qemu, hlt_loop, serial};
+use rust_os::memory;
+use rust_os::memory::frame_allocator::init_bitmap_frame_allocator;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use bootloader::bootinfo::BootInfo;
+
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    rust_os::gdt::init();
+
+    let mut recursive_page_table = unsafe { memory::init(boot_info.p4_table_addr as usize) };
+    let mut frame_allocator = init_bitmap_frame_allocator(&boot_info.memory_map);
+    allocator::init_heap(&mut recursive_page_table, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // an allocation larger than the heap goes through the allocation error handler to
+    // out_of_memory, whose report is checked by the panic handler
+    serial::start_capture();
+    let buffer: Vec<u8> = Vec::with_capacity(allocator::HEAP_SIZE as usize * 2);
+
+    serial::stop_capture();
+    serial_println!("failed");
+    serial_println!("Allocated {} bytes on a smaller heap", buffer.capacity());
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let captured = serial::stop_capture();
+    let report = captured.as_str();
+    if report.starts_with("out of memory subsystem=heap error=HeapExhausted") {
+        serial_println!("ok");
+    } else {
+        serial_println!("failed");
+        serial_println!("{}", info);
+        serial_println!("Unexpected report:\n{}", report);
+    }
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+#[macro_use]
+extern crate rust_os;
+extern crate x86_64;
+extern crate bootloader;
+
+use rust_os::{exit_qemu, hlt_loop};
+use rust_os::memory::{self, map_zeroed_page, unmap_range, MemoryError};
+use rust_os::memory::frame_allocator::init_bitmap_frame_allocator;
+use core::panic::PanicInfo;
+use bootloader::bootinfo::BootInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    rust_os::gdt::init();
+
+    let mut recursive_page_table = unsafe { memory::init(boot_info.p4_table_addr as usize) };
+    let mut frame_allocator = init_bitmap_frame_allocator(&boot_info.memory_map);
+
+    // map pages until the frames run out, which must be reported instead of panicking
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = Page::containing_address(VirtAddr::new(0x_1000_0000_0000));
+    let mut end = start;
+    let error = loop {
+        match map_zeroed_page(end, flags, &mut recursive_page_table, &mut frame_allocator) {
+            Ok(()) => end += 1,
+            Err(err) => break err,
+        }
+    };
+
+    if error != MemoryError::FrameAllocationFailed {
+        serial_println!("failed");
+        serial_println!("Unexpected error: {}", error);
+    } else if frame_allocator.free_frames() > 1 {
+        // a single frame may be left: when the last page also needed a page table, there
+        // was no frame for it and map_zeroed_page gave the page's frame back
+        serial_println!("failed");
+        serial_println!("{} frame(s) left after running out of memory", frame_allocator.free_frames());
+    } else {
+        // the frames can be used again once the pages are unmapped
+        let pages = Page::range(start, end);
+        let unmapped = unmap_range(pages, &mut recursive_page_table, &mut frame_allocator)
+            .expect("unmapping the pages failed");
+        if unmapped == end - start && frame_allocator.free_frames() as u64 >= unmapped {
+            serial_println!("ok");
+        } else {
+            serial_println!("failed");
+            serial_println!("{} of {} page(s) unmapped, {} frame(s) free", unmapped, end - start,
+                            frame_allocator.free_frames());
+        }
+    }
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
@@ -0,0 +1,78 @@
+#![feature(abi_x86_interrupt)]
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+#[macro_use]
+extern crate rust_os;
+extern crate x86_64;
+extern crate bootloader;
+#[macro_use]
+extern crate lazy_static;
+
+use rust_os::{exit_qemu, hlt_loop};
+use rust_os::memory::{self, protection};
+use core::panic::PanicInfo;
+use bootloader::bootinfo::BootInfo;
+use x86_64::structures::idt::{ExceptionStackFrame, InterruptDescriptorTable, PageFaultErrorCode};
+
+pub fn init_idt() { IDT.load(); }
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(page_fault_handler);
+
+        idt
+    };
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    _stack_frame: &mut ExceptionStackFrame, error_code: PageFaultErrorCode
+) {
+    // the page is present but read-only
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(expected) {
+        serial_println!("ok");
+    } else {
+        serial_println!("failed");
+        serial_println!("Unexpected error code: {:?}", error_code);
+    }
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
+
+// the write target, never called
+fn write_target() {}
+
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    rust_os::gdt::init();
+    init_idt();
+
+    let mut recursive_page_table = unsafe { memory::init(boot_info.p4_table_addr as usize) };
+    protection::enforce_wx(&mut recursive_page_table);
+
+    // overwrite the first instruction of a function in .text
+    let code = write_target as *const () as *mut u8;
+    unsafe { code.write_volatile(0xc3) };
+
+    serial_println!("failed");
+    serial_println!("No exception occurred");
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
+
+/// This function is called on panic.
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("failed");
+    serial_println!("{}", info);
+
+    unsafe { exit_qemu(); }
+    hlt_loop();
+}
@@ -0,0 +1,541 @@
+use core::fmt;
+use core::intrinsics;
+use x86_64::registers::control::{Cr0, Cr2, Cr3};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::idt::{ExceptionStackFrame, PageFaultErrorCode};
+use gdt::{self, RawDescriptor};
+use spin::Once;
+use {backtrace, exit_qemu_with, hlt_loop, serial, vga_buffer, QemuExitCode};
+
+/// Vector of the page fault exception, the only one the stubs can return from.
+pub const PAGE_FAULT_VECTOR: u8 = 14;
+
+// Names of the exception vectors, see the fields of InterruptDescriptorTable
+const EXCEPTION_NAMES: [&str; 32] = [
+    "divide_by_zero", "debug", "non_maskable_interrupt", "breakpoint", "overflow",
+    "bound_range_exceeded", "invalid_opcode", "device_not_available", "double_fault",
+    "coprocessor_segment_overrun", "invalid_tss", "segment_not_present",
+    "stack_segment_fault", "general_protection_fault", "page_fault", "reserved",
+    "x87_floating_point", "alignment_check", "machine_check", "simd_floating_point",
+    "virtualization", "reserved", "reserved", "reserved", "reserved", "reserved",
+    "reserved", "reserved", "reserved", "reserved", "security_exception", "reserved",
+];
+
+/// Returns the name of the exception vector, in snake case.
+pub fn exception_name(vector: u8) -> &'static str {
+    EXCEPTION_NAMES.get(vector as usize).cloned().unwrap_or("unknown")
+}
+
+/// Returns `true` if the CPU pushes an error code for the exception vector.
+pub fn has_error_code(vector: u8) -> bool {
+    match vector {
+        8 | 10 | 11 | 12 | 13 | 14 | 17 | 30 => true,
+        _ => false,
+    }
+}
+
+/// Returns `true` if the error code of the exception vector is a `SelectorErrorCode`:
+/// for invalid TSS, segment not present, stack segment and general protection faults.
+pub fn has_selector_error_code(vector: u8) -> bool {
+    match vector {
+        10 | 11 | 12 | 13 => true,
+        _ => false,
+    }
+}
+
+/// The descriptor table a selector error code refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// The error code of segment related faults: the selector, or the IDT vector, whose
+/// descriptor caused the fault. General protection faults not caused by a selector
+/// have the error code 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    /// Whether the fault happened while delivering an event from outside the
+    /// program, e.g. a hardware interrupt.
+    pub fn is_external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(&self) -> DescriptorTable {
+        if self.0 & 0b10 != 0 {
+            DescriptorTable::Idt
+        } else if self.0 & 0b100 != 0 {
+            DescriptorTable::Ldt
+        } else {
+            DescriptorTable::Gdt
+        }
+    }
+
+    /// Index of the descriptor in its table, the vector for the IDT.
+    pub fn index(&self) -> u16 {
+        (self.0 >> 3 & 0x1fff) as u16
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "index {} in {:?}", self.index(), self.table())?;
+        if self.is_external() {
+            write!(f, ", external")?;
+        }
+        Ok(())
+    }
+}
+
+/// The general purpose registers, in the order the stubs push them: the last pushed
+/// register is at the lowest address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct SavedRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// The stack of an exception stub when it calls into Rust: the saved registers, the
+/// error code (0 for exceptions without one) and the frame pushed by the CPU.
+#[repr(C)]
+pub struct ExceptionContext {
+    pub registers: SavedRegisters,
+    pub error_code: u64,
+    pub frame: ExceptionStackFrame,
+}
+
+/// Control registers and EFER at the time of the report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+impl ControlRegisters {
+    /// Reads the registers of the executing CPU.
+    pub fn read() -> ControlRegisters {
+        let cr4: u64;
+        // unsafe: only reads the register
+        unsafe { asm!("mov %cr4, $0" : "=r"(cr4)) };
+        let (p4_frame, cr3_flags) = Cr3::read();
+        ControlRegisters {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: p4_frame.start_address().as_u64() | cr3_flags.bits(),
+            cr4,
+            efer: Efer::read_raw(),
+        }
+    }
+}
+
+/// Segment selectors. CS and SS are those of the interrupted code, the others are
+/// not changed by exceptions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segments {
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
+    pub es: u16,
+    pub fs: u16,
+    pub gs: u16,
+}
+
+impl Segments {
+    fn read(frame: &ExceptionStackFrame) -> Segments {
+        let (ds, es, fs, gs): (u16, u16, u16, u16);
+        // unsafe: only reads the segment registers
+        unsafe {
+            asm!("mov %ds, $0" : "=r"(ds));
+            asm!("mov %es, $0" : "=r"(es));
+            asm!("mov %fs, $0" : "=r"(fs));
+            asm!("mov %gs, $0" : "=r"(gs));
+        }
+        Segments { cs: frame.code_segment as u16, ss: frame.stack_segment as u16, ds, es, fs, gs }
+    }
+}
+
+/// Everything known about a fatal exception.
+pub struct CrashReport {
+    pub vector: u8,
+    // None for exceptions without error code
+    pub error_code: Option<u64>,
+    pub registers: SavedRegisters,
+    pub frame: ExceptionStackFrame,
+    pub control: ControlRegisters,
+    pub segments: Segments,
+    // the decoded error code of segment related faults caused by a selector
+    pub selector: Option<SelectorErrorCode>,
+    // the GDT entry the selector refers to
+    pub descriptor: Option<RawDescriptor>,
+}
+
+impl CrashReport {
+    /// Collects the state of the exception from the context saved by its stub and the
+    /// registers of the CPU.
+    pub fn capture(vector: u8, context: &ExceptionContext) -> CrashReport {
+        let selector = if has_selector_error_code(vector) && context.error_code != 0 {
+            Some(SelectorErrorCode(context.error_code))
+        } else {
+            None
+        };
+        // the kernel has no LDT, and the IDT entries are all set up the same way
+        let descriptor = selector
+            .filter(|selector| selector.table() == DescriptorTable::Gdt)
+            .and_then(|selector| gdt::read_descriptor(selector.index()));
+        CrashReport {
+            vector,
+            error_code: if has_error_code(vector) { Some(context.error_code) } else { None },
+            registers: context.registers,
+            frame: context.frame.clone(),
+            control: ControlRegisters::read(),
+            segments: Segments::read(&context.frame),
+            selector,
+            descriptor,
+        }
+    }
+
+    // Registers as name and value, in the order they are printed
+    fn general_registers(&self) -> [(&'static str, u64); 18] {
+        let r = &self.registers;
+        [
+            ("rax", r.rax), ("rbx", r.rbx), ("rcx", r.rcx), ("rdx", r.rdx),
+            ("rsi", r.rsi), ("rdi", r.rdi), ("rbp", r.rbp), ("rsp", self.frame.stack_pointer.as_u64()),
+            ("r8", r.r8), ("r9", r.r9), ("r10", r.r10), ("r11", r.r11),
+            ("r12", r.r12), ("r13", r.r13), ("r14", r.r14), ("r15", r.r15),
+            ("rip", self.frame.instruction_pointer.as_u64()), ("rflags", self.frame.cpu_flags),
+        ]
+    }
+
+    fn other_registers(&self) -> [(&'static str, u64); 11] {
+        let (c, s) = (&self.control, &self.segments);
+        [
+            ("cr0", c.cr0), ("cr2", c.cr2), ("cr3", c.cr3), ("cr4", c.cr4), ("efer", c.efer),
+            ("cs", u64::from(s.cs)), ("ss", u64::from(s.ss)), ("ds", u64::from(s.ds)),
+            ("es", u64::from(s.es)), ("fs", u64::from(s.fs)), ("gs", u64::from(s.gs)),
+        ]
+    }
+
+    /// Returns the report as a block of `key=value` lines for the host.
+    pub fn serial<'a>(&'a self) -> SerialReport<'a> {
+        SerialReport(self)
+    }
+}
+
+// Writes the name upper case with spaces, as the other exception messages
+struct UpperName(&'static str);
+
+impl fmt::Display for UpperName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            fmt::Write::write_char(f, if c == '_' { ' ' } else { c.to_ascii_uppercase() })?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the report for the screen: three registers per line.
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EXCEPTION: {} (vector {}", UpperName(exception_name(self.vector)), self.vector)?;
+        if let Some(error_code) = self.error_code {
+            write!(f, ", error code {:#x}", error_code)?;
+        }
+        writeln!(f, ")")?;
+        if let Some(selector) = self.selector {
+            write!(f, "selector: {}", selector)?;
+            match self.descriptor {
+                Some(descriptor) => writeln!(f, ", descriptor {:#018x}\n  {}", descriptor.0, descriptor)?,
+                None => writeln!(f)?,
+            }
+        }
+        let general = self.general_registers();
+        let other = self.other_registers();
+        for line in general.chunks(3).chain(other[..5].chunks(3)) {
+            for &(name, value) in line {
+                write!(f, "{:>6} {:#018x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        for &(name, value) in other[5..].iter() {
+            write!(f, "{:>3} {:#06x} ", name, value)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// The crash report as machine-parseable block, see `CrashReport::serial`.
+pub struct SerialReport<'a>(&'a CrashReport);
+
+impl<'a> fmt::Display for SerialReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let report = self.0;
+        writeln!(f, "crash report begin")?;
+        writeln!(f, "exception={}", exception_name(report.vector))?;
+        writeln!(f, "vector={}", report.vector)?;
+        if let Some(error_code) = report.error_code {
+            writeln!(f, "error_code={:#x}", error_code)?;
+        }
+        if let Some(selector) = report.selector {
+            writeln!(f, "selector_index={}", selector.index())?;
+            writeln!(f, "selector_table={}", match selector.table() {
+                DescriptorTable::Gdt => "gdt",
+                DescriptorTable::Idt => "idt",
+                DescriptorTable::Ldt => "ldt",
+            })?;
+            writeln!(f, "selector_external={}", selector.is_external() as u8)?;
+        }
+        if let Some(descriptor) = report.descriptor {
+            writeln!(f, "descriptor={:#x}", descriptor.0)?;
+        }
+        for &(name, value) in report.general_registers().iter().chain(report.other_registers().iter()) {
+            writeln!(f, "{}={:#x}", name, value)?;
+        }
+        writeln!(f, "crash report end")
+    }
+}
+
+// Replaces exiting QEMU at the end of report, see set_exit_handler
+static EXIT_HANDLER: Once<fn(QemuExitCode) -> !> = Once::new();
+
+/// Makes `report` call the handler with the exit code instead of exiting QEMU, e.g. for
+/// test binaries checking the report. Only the first handler set is used.
+pub fn set_exit_handler(handler: fn(QemuExitCode) -> !) {
+    EXIT_HANDLER.call_once(|| handler);
+}
+
+/// Prints the crash report of the exception to the screen and to the host, then exits
+/// QEMU with `QemuExitCode::Failed`.
+///
+/// Takes no lock, since the exception may have interrupted its holder: the report is
+/// written to the serial port directly, and left off the screen if the VGA writer is
+/// locked.
+pub fn report(vector: u8, context: &ExceptionContext) -> ! {
+    let report = CrashReport::capture(vector, context);
+    vga_buffer::try_print(format_args!("{}\n", report));
+    serial::force_print(format_args!("{}", report.serial()));
+    // unsafe: the stubs saved the frame pointer of the interrupted code
+    unsafe {
+        backtrace::print_from(context.frame.instruction_pointer.as_u64() as usize,
+                              context.registers.rbp as usize);
+    }
+
+    if let Some(handler) = EXIT_HANDLER.try() {
+        handler(QemuExitCode::Failed);
+    }
+    // unsafe: the kernel can't continue anyway
+    unsafe { exit_qemu_with(QemuExitCode::Failed) };
+    hlt_loop();
+}
+
+// Called by the stubs. Page faults the memory manager resolves return to the stub,
+// which resumes the interrupted code; every other exception is fatal
+extern "C" fn handle_exception(context: &mut ExceptionContext, vector: u64) {
+    use memory;
+    use memory::page_fault::PageFaultReport;
+
+    let vector = vector as u8;
+    if vector == PAGE_FAULT_VECTOR {
+        let addr = Cr2::read();
+        let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+        // first access to a page of a demand paged area, or first write to a
+        // copy-on-write page: the page is mapped or copied now and the faulting
+        // instruction is retried on return
+        if memory::handle_page_fault(addr, error_code) {
+            return;
+        }
+        // decodes the error code and shows the page table entries used for the address,
+        // unless the interrupted code holds the VGA writer
+        vga_buffer::try_print(format_args!("{}\n", PageFaultReport::new(addr, error_code)));
+    }
+    report(vector, context);
+}
+
+// Defines a stub entered by the CPU for the exception vector. It saves the general
+// purpose registers, passes them with the error code and the exception frame to
+// handle_exception and restores them if it returns. Exceptions without error code
+// get a 0 in its place, so the context has the same layout for all of them
+macro_rules! exception_stub {
+    ($name:ident, $vector:expr) => {
+        #[naked]
+        pub extern "C" fn $name() -> ! {
+            unsafe {
+                asm!("push 0" :::: "intel", "volatile");
+                exception_stub!(@body $vector);
+            }
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        #[naked]
+        pub extern "C" fn $name() -> ! {
+            unsafe {
+                exception_stub!(@body $vector);
+            }
+        }
+    };
+    (@body $vector:expr) => {
+        // the CPU aligns the stack to 16 bytes before pushing the frame and the error
+        // code (6 quadwords), so after the 15 registers it is 8 bytes off
+        asm!("push rax
+              push rbx
+              push rcx
+              push rdx
+              push rsi
+              push rdi
+              push rbp
+              push r8
+              push r9
+              push r10
+              push r11
+              push r12
+              push r13
+              push r14
+              push r15
+              mov rdi, rsp
+              mov rsi, $0
+              sub rsp, 8
+              call $1
+              add rsp, 8
+              pop r15
+              pop r14
+              pop r13
+              pop r12
+              pop r11
+              pop r10
+              pop r9
+              pop r8
+              pop rbp
+              pop rdi
+              pop rsi
+              pop rdx
+              pop rcx
+              pop rbx
+              pop rax
+              add rsp, 8
+              iretq"
+             :: "i"($vector), "i"(handle_exception as extern "C" fn(&mut ExceptionContext, u64))
+             : "memory" : "intel", "volatile");
+        intrinsics::unreachable();
+    };
+}
+
+exception_stub!(divide_by_zero_stub, 0);
+exception_stub!(debug_stub, 1);
+exception_stub!(non_maskable_interrupt_stub, 2);
+exception_stub!(overflow_stub, 4);
+exception_stub!(bound_range_exceeded_stub, 5);
+exception_stub!(invalid_opcode_stub, 6);
+exception_stub!(device_not_available_stub, 7);
+exception_stub!(double_fault_stub, 8, error_code);
+exception_stub!(invalid_tss_stub, 10, error_code);
+exception_stub!(segment_not_present_stub, 11, error_code);
+exception_stub!(stack_segment_fault_stub, 12, error_code);
+exception_stub!(general_protection_fault_stub, 13, error_code);
+exception_stub!(page_fault_stub, 14, error_code);
+exception_stub!(x87_floating_point_stub, 16);
+exception_stub!(alignment_check_stub, 17, error_code);
+exception_stub!(machine_check_stub, 18);
+exception_stub!(simd_floating_point_stub, 19);
+exception_stub!(virtualization_stub, 20);
+exception_stub!(security_exception_stub, 30, error_code);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::format;
+    use x86_64::VirtAddr;
+    use std::string::String;
+    use core::mem;
+
+    fn report() -> CrashReport {
+        CrashReport {
+            vector: 13,
+            error_code: Some(0x10),
+            registers: SavedRegisters { rax: 0xdead, r15: 0xbeef, ..SavedRegisters::default() },
+            frame: ExceptionStackFrame {
+                instruction_pointer: VirtAddr::new(0x20_1234),
+                code_segment: 0x8,
+                cpu_flags: 0x202,
+                stack_pointer: VirtAddr::new(0x5000_0000_7ff8),
+                stack_segment: 0,
+            },
+            control: ControlRegisters { cr0: 0x8001_0011, cr2: 0, cr3: 0x1000, cr4: 0x20, efer: 0xd00 },
+            segments: Segments { cs: 0x8, ss: 0, ds: 0, es: 0, fs: 0, gs: 0 },
+            selector: Some(SelectorErrorCode(0x10)),
+            descriptor: Some(RawDescriptor(0x0020_9800_0000_0000)),
+        }
+    }
+
+    #[test]
+    fn context_matches_stub_layout() {
+        // 15 registers, the error code and 5 quadwords pushed by the CPU
+        assert_eq!(mem::size_of::<ExceptionContext>(), 21 * 8);
+    }
+
+    #[test]
+    fn serial_report_is_parseable() {
+        let text = format!("{}", report().serial());
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("crash report begin"));
+        let fields: std::vec::Vec<(String, String)> = lines
+            .take_while(|&line| line != "crash report end")
+            .map(|line| {
+                let mut parts = line.splitn(2, '=');
+                (String::from(parts.next().unwrap()), String::from(parts.next().expect("line without =")))
+            })
+            .collect();
+        let field = |name: &str| fields.iter().find(|field| field.0 == name).map(|field| field.1.as_str());
+        assert_eq!(field("exception"), Some("general_protection_fault"));
+        assert_eq!(field("error_code"), Some("0x10"));
+        assert_eq!(field("rax"), Some("0xdead"));
+        assert_eq!(field("r15"), Some("0xbeef"));
+        assert_eq!(field("rip"), Some("0x201234"));
+        assert_eq!(field("efer"), Some("0xd00"));
+        assert_eq!(field("selector_index"), Some("2"));
+        assert_eq!(field("selector_table"), Some("gdt"));
+        assert_eq!(field("descriptor"), Some("0x20980000000000"));
+        assert_eq!(fields.len(), 3 + 4 + 18 + 11);
+        assert!(text.ends_with("crash report end\n"));
+    }
+
+    #[test]
+    fn screen_report_names_exception() {
+        let text = format!("{}", report());
+        assert!(text.starts_with("EXCEPTION: GENERAL PROTECTION FAULT (vector 13, error code 0x10)\n"));
+        assert!(text.lines().all(|line| line.len() <= 80));
+        assert!(text.contains("\nselector: index 2 in Gdt, descriptor 0x0020980000000000\n"));
+    }
+
+    #[test]
+    fn decodes_selector_error_codes() {
+        // the TSS selector of the GDT
+        let tss = SelectorErrorCode(0x10);
+        assert_eq!((tss.index(), tss.table(), tss.is_external()), (2, DescriptorTable::Gdt, false));
+        // vector 32 of the IDT, raised by a hardware interrupt
+        let idt = SelectorErrorCode(32 << 3 | 0b011);
+        assert_eq!((idt.index(), idt.table(), idt.is_external()), (32, DescriptorTable::Idt, true));
+        // the table indicator bit only matters outside the IDT
+        assert_eq!(SelectorErrorCode(0b110).table(), DescriptorTable::Idt);
+        assert_eq!(SelectorErrorCode(5 << 3 | 0b100).table(), DescriptorTable::Ldt);
+        assert_eq!(format!("{}", idt), "index 32 in Idt, external");
+    }
+}
@@ -1,9 +1,17 @@
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use x86_64::structures::gdt::SegmentSelector;
+use x86_64::structures::DescriptorTablePointer;
+use memory::{self, stack};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// Size of the double fault stack allocated by init_kernel_stacks: 16 KiB
+pub const DOUBLE_FAULT_STACK_PAGES: u64 = 4;
 
 // Used to add selectors to the static GDT
 struct Selectors {
@@ -11,63 +19,203 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
+// Mutable, so the double fault stack can be replaced once kernel stacks can be
+// allocated. The CPU reads the IST entries when an exception arrives, the loaded TSS
+// doesn't need to be reloaded for a new entry to take effect
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+// Created by the first init call, which is the only one allowed
+static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
+// Set by init_kernel_stacks, which may only replace the double fault stack once
+static KERNEL_STACKS: AtomicBool = AtomicBool::new(false);
+
+/// Loads the GDT and TSS using a static array as double fault stack.
+///
+/// The static stack has no guard page, `init_kernel_stacks` replaces it once memory
+/// management is set up. Must be called only once, as early as possible: until the
+/// GDT and IDT are loaded any exception triple faults.
 pub fn init() {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
+    // loading the TSS a second time would raise a general protection fault, as it is
+    // marked busy by the first load
+    assert!(GDT.try().is_none(), "GDT already loaded");
+
+    // TSS: holds two stack tables: (Interrupt Stack Table & privilege stack table)
+    // the PST is used by CPU when privilege level changes (exception while CPU
+    // in User mode (level 3) and switches to kernel mode (level 0).
+    // In this case CPU would switch to the 0th stack in PST since 0 is the
+    // target privilege level
+    // define the Interrupt Stack Table's 0th index (of 7 total)
+    // for the double fault handler. This is done to prevent the
+    // the double fault exception handler from being swapped out,
+    // or unavailable cause a triple fault! The CPU automatically
+    // switches the stack for you when the interrupt occurs.
+    // unsafe: the TSS isn't loaded yet, nothing else accesses it
+    let tss = unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = static_double_fault_stack();
+        &TSS
+    };
+
+    // create a new GDT with a kernel code segment and our TSS segment
+    let gdt = GDT.call_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        (gdt, Selectors { code_selector, tss_selector })
+    });
+
     // load the actual GDT: references the actual GDT at
     // index 0 of the static GDT tuple
-    GDT.0.load();
+    gdt.0.load();
 
     // unsafe because might be possible to break memory safety
     // by loading invalid selectors.
     unsafe {
         // reload the code segment register with the new one in the GDT tuples index 1
-        set_cs(GDT.1.code_selector);
+        set_cs(gdt.1.code_selector);
         // tell CPU to use TSS in the GDT tuples index 1
-        load_tss(GDT.1.tss_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
 
-lazy_static! {
-    // create a new GDT with a kernel code segment and our TSS segment
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+/// Replaces the static double fault stack with one from the kernel stack allocator,
+/// so the stack is protected by a guard page.
+///
+/// Requires `init` and `memory::init_kernel_memory` to be called before. Must be
+/// called only once.
+pub fn init_kernel_stacks() {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    assert!(GDT.try().is_some(), "GDT not loaded");
+    assert!(!KERNEL_STACKS.swap(true, Ordering::SeqCst), "double fault stack already replaced");
+
+    let double_fault_stack = stack::alloc_kernel_stack(DOUBLE_FAULT_STACK_PAGES)
+        .unwrap_or_else(|err| memory::out_of_memory("double fault stack", err));
+    // unsafe: the CPU only reads the entry when a double fault arrives, and a double
+    // fault can't interrupt this single store with interrupts disabled
+    without_interrupts(|| unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack.top();
+    });
 }
 
-lazy_static! {
-    // TSS: holds two stack tables: (Interrupt Stack Table & privilege stack table)
-    // the PST is used by CPU when privilege level changes (exception while CPU
-    // in User mode (level 3) and switches to kernel mode (level 0).
-    // In this case CPU would switch to the 0th stack in PST since 0 is the
-    // target privilege level
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        // define the Interrupt Stack Table's 0th index (of 7 total)
-        // for the double fault handler. This is done to prevent the
-        // the double fault exception handler from being swapped out,
-        // or unavailable cause a triple fault! The CPU automatically
-        // switches the stack for you when the interrupt occurs.
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // create a 4KB stack for double fault
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // Store the top (higher numbered address) of the stack
-            // since x86 stacks grow downward into lower memory address.
-            // No memory management created yet, so no proper way to allocate a
-            // new stack. Using a simple array for storage now. Unsafe required because
-            // compiler can't guarantee race freedom when static mut is referenced.
-            // Must be mutable static else bootload will map to read-only page.
-            // No guard page protecting against stack overflow, so no stack intensive tasks.
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
+// Returns the top of a 4KB static stack for the double fault handler
+fn static_double_fault_stack() -> VirtAddr {
+    const STACK_SIZE: usize = 4096;
+    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+    // Store the top (higher numbered address) of the stack
+    // since x86 stacks grow downward into lower memory address.
+    // Unsafe required because compiler can't guarantee race freedom when
+    // static mut is referenced.
+    // Must be mutable static else bootload will map to read-only page.
+    // No guard page protecting against stack overflow, so no stack intensive tasks.
+    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+    let stack_end = stack_start + STACK_SIZE;
+    stack_end
+}
+
+/// A raw 8 byte GDT entry, decoded for diagnostics and displayed as its fields.
+///
+/// System descriptors like the TSS take two entries in long mode, this is the lower
+/// one with the low 32 bits of the base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawDescriptor(pub u64);
+
+impl RawDescriptor {
+    pub fn base(&self) -> u32 {
+        ((self.0 >> 16) & 0xff_ffff | (self.0 >> 56) << 24) as u32
+    }
+
+    /// The limit in units of the granularity, see `granularity`.
+    pub fn limit(&self) -> u32 {
+        (self.0 & 0xffff | (self.0 >> 48 & 0xf) << 16) as u32
+    }
+
+    /// The segment type, e.g. 0xa for executable and readable code or 0x9 for an
+    /// available 64-bit TSS.
+    pub fn segment_type(&self) -> u8 {
+        (self.0 >> 40 & 0xf) as u8
+    }
+
+    /// `false` for system descriptors, like the TSS.
+    pub fn is_user_segment(&self) -> bool {
+        self.0 & 1 << 44 != 0
+    }
+
+    pub fn privilege_level(&self) -> u8 {
+        (self.0 >> 45 & 0x3) as u8
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.0 & 1 << 47 != 0
+    }
+
+    /// Whether a code segment is a 64-bit one.
+    pub fn is_long_mode(&self) -> bool {
+        self.0 & 1 << 53 != 0
+    }
+
+    /// Whether the limit counts 4 KiB pages instead of bytes.
+    pub fn granularity(&self) -> bool {
+        self.0 & 1 << 55 != 0
+    }
+}
+
+impl fmt::Display for RawDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "base={:#x} limit={:#x}{} type={:#x} {} dpl={}",
+               self.base(), self.limit(), if self.granularity() { " pages" } else { "" },
+               self.segment_type(), if self.is_user_segment() { "user" } else { "system" },
+               self.privilege_level())?;
+        if self.is_present() {
+            write!(f, " present")?;
+        }
+        if self.is_long_mode() {
+            write!(f, " long")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the entry at the index from the GDT loaded on the CPU, `None` if the index
+/// is beyond its limit.
+pub fn read_descriptor(index: u16) -> Option<RawDescriptor> {
+    let mut pointer = DescriptorTablePointer { limit: 0, base: 0 };
+    // unsafe: only stores the GDT register to the pointer
+    unsafe { asm!("sgdt ($0)" :: "r"(&mut pointer as *mut DescriptorTablePointer) : "memory") };
+    let (base, limit) = (pointer.base, u64::from(pointer.limit));
+
+    // the limit is the offset of the last byte of the table
+    let offset = u64::from(index) * 8;
+    if offset + 7 > limit {
+        return None;
+    }
+    // unsafe: the entry is within the loaded GDT, which stays mapped
+    Some(RawDescriptor(unsafe { ptr::read_volatile((base + offset) as *const u64) }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_descriptors() {
+        let code = match Descriptor::kernel_code_segment() {
+            Descriptor::UserSegment(bits) => RawDescriptor(bits),
+            Descriptor::SystemSegment(..) => panic!("code segment is a system segment"),
         };
-        tss
-    };
-}
\ No newline at end of file
+        assert_eq!(code.segment_type() & 0x8, 0x8);
+        assert!(code.is_user_segment() && code.is_present() && code.is_long_mode());
+        assert_eq!(code.privilege_level(), 0);
+
+        // a TSS at 0x1234_5678 with the limit 0x67
+        let tss = RawDescriptor(0x1200_8934_5678_0067);
+        assert_eq!(tss.base(), 0x1234_5678);
+        assert_eq!(tss.limit(), 0x67);
+        assert_eq!(tss.segment_type(), 0x9);
+        assert!(!tss.is_user_segment() && tss.is_present() && !tss.is_long_mode());
+        assert_eq!(std::format!("{}", tss),
+                   "base=0x12345678 limit=0x67 type=0x9 system dpl=0 present");
+    }
+}
@@ -1,11 +1,18 @@
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
-use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, ExceptionStackFrame };
+use x86_64::structures::idt::{InterruptDescriptorTable, ExceptionStackFrame };
+
+pub mod irq;
+
+pub use self::irq::{register_irq, unregister_irq, IrqError, IrqHandler};
 
 pub const PIC_1_OFFSET: u8 = 32;    // offset interrupts to 32 (where CPU exceptions end)
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;  // start secondary PIC exceptions after 8 for first
-pub const TIMER_INTERRUPT_ID: u8 = PIC_1_OFFSET;    // timer interrupt (0 + offset)
-pub const KEYBOARD_INTERRUPT_ID: u8 = PIC_1_OFFSET + 1;     // keyboard interrupt
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const SERIAL_IRQ: u8 = 4;       // first serial port (COM1)
+pub const TIMER_INTERRUPT_ID: u8 = PIC_1_OFFSET + TIMER_IRQ;    // timer interrupt (0 + offset)
+pub const KEYBOARD_INTERRUPT_ID: u8 = PIC_1_OFFSET + KEYBOARD_IRQ;     // keyboard interrupt
 pub const SYS_CALL_ID: u8 = 0x80;       // base 10: 128
 
 // unsafe: wrong offset could cause undefined behavior
@@ -13,11 +20,84 @@ pub const SYS_CALL_ID: u8 = 0x80;       // base 10: 128
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// The controller hardware interrupts are delivered by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The local APIC and the IOAPICs.
+    Apic,
+    /// The 8259 PICs, if there is no APIC or no MADT describing it.
+    Pic,
+}
+
+/// Sets up the interrupt controllers: the APICs described by the ACPI MADT, with the
+/// PICs masked, or the PICs if there are no APICs. Returns the controller used.
+///
+/// IRQs are masked, except those with handlers registered by `register_irq`. The
+/// IOAPIC routes them to the vectors the PICs would use, so the IDT entries are the
+/// same with either controller.
+///
+/// Must be called with interrupts disabled, after the heap and the kernel memory are
+/// initialized.
+pub fn init_interrupt_controller() -> InterruptController {
+    use acpi;
+    use apic;
+
+    // remapped even if they are masked afterwards, so their spurious interrupts don't
+    // arrive as exceptions
+    // unsafe: possible undefined behavior if PIC misconfigured
+    unsafe { PICS.lock().initialize() };
+    // all IRQs of the PICs masked, irq::init unmasks those with handlers
+    apic::mask_pics();
+
+    register_irq(TIMER_IRQ, timer_irq_handler).expect("timer handler registration failed");
+
+    let madt = if apic::is_supported() { acpi::madt() } else { None };
+    let controller = match madt {
+        Some(ref madt) if !madt.io_apics.is_empty() => {
+            match apic::init(madt, PIC_1_OFFSET) {
+                Ok(()) => InterruptController::Apic,
+                Err(err) => {
+                    // the PICs are masked above but still set up, irq::init unmasks the
+                    // IRQs with handlers on them again
+                    serial_println!("apic: mapping failed ({}), using the PICs", err);
+                    InterruptController::Pic
+                }
+            }
+        }
+        _ => InterruptController::Pic,
+    };
+    irq::init(controller);
+    controller
+}
+
+/// Signals the end of the interrupt with the vector to the controller that delivered
+/// it. Called after the handlers registered for an IRQ ran.
+pub fn end_of_interrupt(vector: u8) {
+    use apic;
+
+    if apic::is_active() {
+        apic::end_of_interrupt();
+    } else {
+        // unsafe: incorrect interrupt vector number could result in deleting unsent interrupt
+        // causing system to hang
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) }
+    }
+}
+
 // Initialize the CPUs IDT
 pub fn init_idt() {
     IDT.load();
 }
 
+// The IDT takes x86-interrupt functions, which the CPU enters the same way as the
+// stubs. The stubs take care of the registers and the error code themselves
+macro_rules! stub {
+    ($name:ident) => {
+        // unsafe: the stubs are entered by the CPU with the exception frame only
+        unsafe { ::core::mem::transmute(::crash::$name as extern "C" fn() -> !) }
+    };
+}
+
 // Static IDT for CPU to reference during exceptions
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -25,41 +105,43 @@ lazy_static! {
 
         let mut idt = InterruptDescriptorTable::new();
 
-        idt.divide_by_zero.set_handler_fn(divide_by_zero_handler);
-        idt.debug.set_handler_fn(debug_handler);
+        // Fatal exceptions and page faults enter through stubs saving all registers
+        // for the crash report, see crash.rs
+        idt.divide_by_zero.set_handler_fn(stub!(divide_by_zero_stub));
+        idt.debug.set_handler_fn(stub!(debug_stub));
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
-        idt.overflow.set_handler_fn(overflow_interrupt_handler);
-        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.non_maskable_interrupt.set_handler_fn(stub!(non_maskable_interrupt_stub));
+        idt.overflow.set_handler_fn(stub!(overflow_stub));
+        idt.bound_range_exceeded.set_handler_fn(stub!(bound_range_exceeded_stub));
+        idt.invalid_opcode.set_handler_fn(stub!(invalid_opcode_stub));
+        idt.device_not_available.set_handler_fn(stub!(device_not_available_stub));
 
         // unsafe: caller must ensure that used stack index is valid
         // and not already used for another exception
         unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
+            idt.double_fault.set_handler_fn(stub!(double_fault_stub))
                 // tells CPU to switch to this stack before invoking handler
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
          }
 
-        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
-        idt.alignment_check.set_handler_fn(alignment_check_handler);
-        idt.machine_check.set_handler_fn(machine_check_handler);
-        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
-        idt.virtualization.set_handler_fn(virtualization_handler);
-        idt.security_exception.set_handler_fn(security_exception_handler);
-
-        // PIC interrupts
-        let timer_interrupt_id = usize::from(TIMER_INTERRUPT_ID);
-        idt[timer_interrupt_id].set_handler_fn(timer_interrupt_handler);
-
-        let keyboard_interrupt_id = usize::from(KEYBOARD_INTERRUPT_ID);
-        idt[keyboard_interrupt_id].set_handler_fn(keyboard_interrupt_handler);
+        idt.invalid_tss.set_handler_fn(stub!(invalid_tss_stub));
+        idt.segment_not_present.set_handler_fn(stub!(segment_not_present_stub));
+        idt.stack_segment_fault.set_handler_fn(stub!(stack_segment_fault_stub));
+        idt.general_protection_fault.set_handler_fn(stub!(general_protection_fault_stub));
+        idt.page_fault.set_handler_fn(stub!(page_fault_stub));
+        idt.x87_floating_point.set_handler_fn(stub!(x87_floating_point_stub));
+        idt.alignment_check.set_handler_fn(stub!(alignment_check_stub));
+        idt.machine_check.set_handler_fn(stub!(machine_check_stub));
+        idt.simd_floating_point.set_handler_fn(stub!(simd_floating_point_stub));
+        idt.virtualization.set_handler_fn(stub!(virtualization_stub));
+        idt.security_exception.set_handler_fn(stub!(security_exception_stub));
+
+        // Hardware interrupts, dispatched to the handlers registered for the IRQ
+        irq::set_idt_entries(&mut idt);
+
+        // APIC interrupts
+        let spurious_interrupt_id = usize::from(::apic::SPURIOUS_INTERRUPT_ID);
+        idt[spurious_interrupt_id].set_handler_fn(spurious_interrupt_handler);
 
         // Sys call interrupt
         let sys_call_interrupt_id = usize::from(SYS_CALL_ID);
@@ -75,25 +157,8 @@ lazy_static! {
 // Traps: Traps are reported immediately after the execution of the trapping instruction.
 // Aborts: Some severe unrecoverable error.
 
-/// Fault: occurs when dividing any number by 0
-extern "x86-interrupt" fn divide_by_zero_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault/Trip: debug exceptions
-extern "x86-interrupt" fn debug_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
-    hlt_loop();
-}
+// All exceptions but breakpoints are fatal or page faults, both are handled by the
+// stubs in crash.rs
 
 /// Trap: Handler for breakpoint exception
 extern "x86-interrupt" fn breakpoint_handler(
@@ -102,217 +167,17 @@ extern "x86-interrupt" fn breakpoint_handler(
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-/// Interrupt: Handler for non maskable interrupts
-extern "x86-interrupt" fn non_maskable_interrupt_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: NON-MASKABLE\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Trip: Handler for
-extern "x86-interrupt" fn overflow_interrupt_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: handles bound range exceeded exception
-extern "x86-interrupt" fn bound_range_exceeded_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: handles invalid opcode exception
-extern "x86-interrupt" fn invalid_opcode_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: handles device not available exception
-extern "x86-interrupt" fn device_not_available_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-// Occurs when a cpu exception is not caught.
-// if not implemented and needed a triple fault will occur
-// this results (mostly) in a complete reboot
-/// About: Handler for double fault exception
-extern "x86-interrupt" fn double_fault_handler(
-    // error_code by definition always 0
-    stack_frame: &mut ExceptionStackFrame, _error_code: u64
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: handles invalid tss exception
-extern "x86-interrupt" fn invalid_tss_handler(
-    stack_frame: &mut ExceptionStackFrame, _error_code: u64
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: INVALID TSS\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: handles segment not present exception
-extern "x86-interrupt" fn segment_not_present_handler(
-    stack_frame: &mut ExceptionStackFrame, _error_code: u64
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: SEGMENT NOT PRESENT\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: handles stack segment fault exception
-extern "x86-interrupt" fn stack_segment_fault_handler(
-    stack_frame: &mut ExceptionStackFrame, _error_code: u64
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: general exception errors
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: &mut ExceptionStackFrame, _error_code: u64
-) {
-    use hlt_loop;
-//    println!("Error code: {}", error_code);
-    println!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: Handler for page fault exception
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut ExceptionStackFrame, _error_code: PageFaultErrorCode
-) {
-    use hlt_loop;
-    // automatically set on page fault to accessed virtual address that caused page fault
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("{:#?}", stack_frame);
-    println!("EXCEPTION: PAGE FAULT\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: Handler for x87 floating point exception
-extern "x86-interrupt" fn x87_floating_point_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: X87 FLOATING POINT\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: Handler for alignment check exception
-extern "x86-interrupt" fn alignment_check_handler(
-    stack_frame: &mut ExceptionStackFrame, _error_code: u64
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: ALIGNMENT CHECK\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Abort: Handler for machine check exception
-extern "x86-interrupt" fn machine_check_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: Handler for simd floating point exception
-extern "x86-interrupt" fn simd_floating_point_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: Handler virtualization exception
-extern "x86-interrupt" fn virtualization_handler(
-    stack_frame: &mut ExceptionStackFrame
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: VIRTUALIZATION\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
-/// Fault: Handler security exception
-extern "x86-interrupt" fn security_exception_handler(
-    stack_frame: &mut ExceptionStackFrame, _error_code: u64
-) {
-    use hlt_loop;
-
-    println!("EXCEPTION: SECURITY EXCEPTION\n{:#?}", stack_frame);
-    hlt_loop();
-}
-
 // Hardware Interrupts
 
 /// Handler to timer interrupts
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: &mut ExceptionStackFrame
-) {
+fn timer_irq_handler(_irq: u8) {
 //    print!(".");
-    // PIC waits for EOI signal notifying ready for next interrupt
-    // unsafe: incorrect interrupt vector number could result in deleting unsent interrupt
-    // causing system to hang
-    unsafe { PICS.lock().notify_end_of_interrupt(TIMER_INTERRUPT_ID) }
 }
 
-/// Handler for keyboard interrupts
-extern "x86-interrupt" fn keyboard_interrupt_handler(
+/// Handler for spurious interrupts of the local APIC, which must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(
     _stack_frame: &mut ExceptionStackFrame
 ) {
-    use x86_64::instructions::port::Port;
-    use keyboard;
-
-    let port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    let key = keyboard::scancode_map(scancode);
-
-    if let Some(key) = key {
-        print!("{}", key);
-    } /*else {
-        // debugging of unmapped scancodes
-        print!(" {} ", scancode);
-    }*/
-    //print!("Exception: breakpoint\n{:#?}", stack_frame);
-    unsafe { PICS.lock().notify_end_of_interrupt(KEYBOARD_INTERRUPT_ID)}
 }
 
 // Software Interrupts
@@ -0,0 +1,244 @@
+use spin::{Once, RwLock};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{ExceptionStackFrame, HandlerFunc, InterruptDescriptorTable};
+use super::{end_of_interrupt, InterruptController, PIC_1_OFFSET};
+use apic;
+
+/// Number of legacy IRQs, the inputs of the two PICs. Each has the vector
+/// `PIC_1_OFFSET + irq` with either controller.
+pub const IRQ_COUNT: usize = 16;
+/// Number of handlers that can share an IRQ.
+pub const MAX_SHARED_HANDLERS: usize = 4;
+// IRQ of the secondary PIC on the primary one
+const CASCADE_IRQ: u8 = 2;
+
+/// Called with the IRQ number when the IRQ is raised. Handlers run with interrupts
+/// disabled and must not acknowledge the interrupt, that is done after all handlers
+/// of the IRQ ran.
+pub type IrqHandler = fn(u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ number is not below `IRQ_COUNT`.
+    InvalidIrq,
+    /// The IRQ already has `MAX_SHARED_HANDLERS` handlers.
+    TooManyHandlers,
+    /// The handler is registered for the IRQ already.
+    AlreadyRegistered,
+    /// The handler to unregister isn't registered for the IRQ.
+    NotRegistered,
+}
+
+// Handlers of each IRQ, in registration order. Only written with interrupts disabled,
+// so the trampolines can't find the lock held by the code they interrupted
+static HANDLERS: RwLock<[[Option<IrqHandler>; MAX_SHARED_HANDLERS]; IRQ_COUNT]> =
+    RwLock::new([[None; MAX_SHARED_HANDLERS]; IRQ_COUNT]);
+// Set once the controller is set up, IRQs are only unmasked from then on
+static CONTROLLER: Once<InterruptController> = Once::new();
+
+/// Adds the handler to the IRQ. The first handler of an IRQ unmasks it.
+///
+/// Handlers can be registered before the interrupt controller is set up, their IRQs
+/// are unmasked once it is.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq);
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let first = add_handler(&mut handlers[irq as usize], handler)?;
+        if first {
+            set_masked(irq, false);
+        }
+        Ok(())
+    })
+}
+
+/// Removes the handler from the IRQ. Removing the last handler masks the IRQ.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq);
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let last = remove_handler(&mut handlers[irq as usize], handler)?;
+        if last {
+            set_masked(irq, true);
+        }
+        Ok(())
+    })
+}
+
+// Adds the handler to the free slot, returns true if it is the first one
+fn add_handler(slots: &mut [Option<IrqHandler>; MAX_SHARED_HANDLERS], handler: IrqHandler)
+               -> Result<bool, IrqError> {
+    if slots.iter().any(|slot| same_handler(*slot, handler)) {
+        return Err(IrqError::AlreadyRegistered);
+    }
+    let first = slots[0].is_none();
+    match slots.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(handler),
+        None => return Err(IrqError::TooManyHandlers),
+    }
+    Ok(first)
+}
+
+// Removes the handler, keeping the order of the others. Returns true if it was the
+// last one
+fn remove_handler(slots: &mut [Option<IrqHandler>; MAX_SHARED_HANDLERS], handler: IrqHandler)
+                  -> Result<bool, IrqError> {
+    let index = slots.iter().position(|slot| same_handler(*slot, handler))
+        .ok_or(IrqError::NotRegistered)?;
+    for i in index..MAX_SHARED_HANDLERS - 1 {
+        slots[i] = slots[i + 1];
+    }
+    slots[MAX_SHARED_HANDLERS - 1] = None;
+    Ok(slots[0].is_none())
+}
+
+fn same_handler(slot: Option<IrqHandler>, handler: IrqHandler) -> bool {
+    slot.map(|registered| registered as usize) == Some(handler as usize)
+}
+
+/// Records the controller delivering the IRQs and unmasks the IRQs with handlers.
+/// Called by `init_interrupt_controller` with all IRQs masked.
+pub(super) fn init(controller: InterruptController) {
+    CONTROLLER.call_once(|| controller);
+    without_interrupts(|| {
+        let handlers = HANDLERS.read();
+        for (irq, slots) in handlers.iter().enumerate() {
+            if slots[0].is_some() {
+                set_masked(irq as u8, false);
+            }
+        }
+    });
+}
+
+// Masks or unmasks the IRQ at the controller. Does nothing before the controller is
+// set up
+fn set_masked(irq: u8, masked: bool) {
+    match CONTROLLER.try() {
+        Some(InterruptController::Apic) => apic::set_irq_masked(irq, masked),
+        Some(InterruptController::Pic) => set_pic_masked(irq, masked),
+        None => {}
+    }
+}
+
+// Sets or clears the bit of the IRQ in the mask register of its PIC. The secondary PIC
+// reaches the CPU through the cascade IRQ, which is unmasked along with its IRQs
+fn set_pic_masked(irq: u8, masked: bool) {
+    let (mut port, bit) = if irq < 8 {
+        (Port::<u8>::new(0x21), irq)
+    } else {
+        (Port::<u8>::new(0xa1), irq - 8)
+    };
+    // unsafe: reading and writing the data port of an initialized PIC accesses its mask
+    unsafe {
+        let mask = port.read();
+        port.write(if masked { mask | 1 << bit } else { mask & !(1 << bit) });
+    }
+    if irq >= 8 && !masked {
+        set_pic_masked(CASCADE_IRQ, false);
+    }
+}
+
+// Returns whether the IRQ is spurious: raised by a PIC for an interrupt request that
+// went away before it was acknowledged, which the PIC reports as its lowest priority
+// IRQ 7 or 15. They arrive on the same vectors while the PICs are masked under the
+// APIC, but aren't in service at the local APIC then
+fn is_spurious(irq: u8) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+    if apic::is_active() {
+        return !apic::is_in_service(PIC_1_OFFSET + irq);
+    }
+    let mut command = if irq < 8 { Port::<u8>::new(0x20) } else { Port::<u8>::new(0xa0) };
+    // unsafe: OCW3 0x0b makes the next read of the command port return the in-service
+    // register, which doesn't change the state of the PIC
+    let in_service = unsafe {
+        command.write(0x0b);
+        command.read()
+    };
+    in_service & (1 << (irq % 8)) == 0
+}
+
+// Runs the handlers of the IRQ, then signals the end of the interrupt
+fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        // nothing to acknowledge, but the primary PIC did deliver a spurious IRQ 15 of
+        // the secondary one through the cascade IRQ
+        if irq == 15 && !apic::is_active() {
+            end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ);
+        }
+        return;
+    }
+    // copied, so handlers don't run with the lock held
+    let slots = HANDLERS.read()[irq as usize];
+    for handler in slots.iter().filter_map(|slot| *slot) {
+        handler(irq);
+    }
+    end_of_interrupt(PIC_1_OFFSET + irq);
+}
+
+// Defines an interrupt handler for each IRQ, dispatching to the registered handlers
+macro_rules! trampolines {
+    ($($irq:expr => $name:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut ExceptionStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        const TRAMPOLINES: [HandlerFunc; IRQ_COUNT] = [$($name),*];
+    };
+}
+
+trampolines!(0 => irq_0, 1 => irq_1, 2 => irq_2, 3 => irq_3, 4 => irq_4, 5 => irq_5,
+             6 => irq_6, 7 => irq_7, 8 => irq_8, 9 => irq_9, 10 => irq_10, 11 => irq_11,
+             12 => irq_12, 13 => irq_13, 14 => irq_14, 15 => irq_15);
+
+/// Sets the IDT entries of all IRQ vectors to the trampolines.
+pub fn set_idt_entries(idt: &mut InterruptDescriptorTable) {
+    for (irq, &trampoline) in TRAMPOLINES.iter().enumerate() {
+        idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(trampoline);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn first(_irq: u8) {}
+    fn second(_irq: u8) {}
+    fn third(_irq: u8) {}
+
+    #[test]
+    fn chains_shared_handlers() {
+        let mut slots = [None; MAX_SHARED_HANDLERS];
+        assert_eq!(add_handler(&mut slots, first), Ok(true));
+        assert_eq!(add_handler(&mut slots, second), Ok(false));
+        assert_eq!(add_handler(&mut slots, first), Err(IrqError::AlreadyRegistered));
+        add_handler(&mut slots, third).unwrap();
+
+        assert_eq!(remove_handler(&mut slots, first), Ok(false));
+        assert_eq!(remove_handler(&mut slots, first), Err(IrqError::NotRegistered));
+        // the remaining handlers keep their order
+        assert!(same_handler(slots[0], second) && same_handler(slots[1], third));
+        assert_eq!(remove_handler(&mut slots, second), Ok(false));
+        assert_eq!(remove_handler(&mut slots, third), Ok(true));
+    }
+
+    #[test]
+    fn limits_shared_handlers() {
+        fn fourth(_irq: u8) {}
+        fn fifth(_irq: u8) {}
+
+        let mut slots = [None; MAX_SHARED_HANDLERS];
+        for &handler in [first as IrqHandler, second, third, fourth].iter() {
+            add_handler(&mut slots, handler).unwrap();
+        }
+        assert_eq!(add_handler(&mut slots, fifth), Err(IrqError::TooManyHandlers));
+    }
+}
@@ -1,4 +1,5 @@
 use spin::Mutex;
+use interrupts::{self, IrqError, KEYBOARD_IRQ};
 
 lazy_static! {
     static ref SHIFT_ACTIVE: Mutex<bool> = {
@@ -96,3 +97,23 @@ pub fn scancode_map(scancode: u8) -> Option<char> {
     };
     transform(key)
 }
+
+/// Registers the handler of keyboard interrupts, which prints the typed characters.
+pub fn init() -> Result<(), IrqError> {
+    interrupts::register_irq(KEYBOARD_IRQ, interrupt_handler)
+}
+
+fn interrupt_handler(_irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    let key = scancode_map(scancode);
+
+    if let Some(key) = key {
+        print!("{}", key);
+    } /*else {
+        // debugging of unmapped scancodes
+        print!(" {} ", scancode);
+    }*/
+}
@@ -1,5 +1,10 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]  // enable usage of unstable x86-interrupt calling convention
+#![feature(alloc)]              // enable usage of the alloc crate (Box, Vec, etc.) without std
+#![feature(alloc_error_handler)]    // enable defining the function called on allocation failures
+#![feature(asm)]                // enable inline assembly, e.g. to read the frame pointer
+#![feature(naked_functions)]    // enable functions without prologue, for the exception stubs
+#![feature(core_intrinsics)]    // enable intrinsics::unreachable at the end of the stubs
 
 #[macro_use]
 extern crate lazy_static;
@@ -9,6 +14,8 @@ extern crate spin;
 extern crate uart_16550;    // as serial interface for port mapped I/O
 extern crate x86_64;
 extern crate pic8259_simple;
+extern crate linked_list_allocator;
+extern crate alloc;
 
 // Unit tests run on host machine, therefore std lib available
 #[cfg(test)]
@@ -18,11 +25,17 @@ extern crate array_init;
 
 #[macro_use]
 pub mod vga_buffer;
+#[macro_use]
 pub mod serial;
 pub mod gdt;
+pub mod acpi;
+pub mod apic;
 pub mod interrupts;
+pub mod crash;
+pub mod backtrace;
 pub mod keyboard;
 pub mod memory;
+pub mod allocator;
 
 // Notify the CPU to halt until the next interrupt arrives rather than
 // the expensive loop
@@ -32,6 +45,15 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+// Called when a heap allocation fails. Reports the failure with the memory usage,
+// then goes through the panic handler
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    let error = memory::MemoryError::HeapExhausted { size: layout.size(), align: layout.align() };
+    memory::out_of_memory("heap", error)
+}
+
 // unsafe: relies on fact that a special QEMU device is attached to the I/O port w/ address 0xf4
 // Provides exiting qemu without a 'proper' shutdown
 pub unsafe fn exit_qemu() {
@@ -41,3 +63,20 @@ pub unsafe fn exit_qemu() {
     let mut port = Port::<u32>::new(0xf4);
     port.write(0);
 }
+
+/// Codes written to the isa-debug-exit device. QEMU exits with `(code << 1) | 1`, so
+/// they can't be confused with QEMU's own exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+// unsafe: same as exit_qemu. Exits with status 33 for Success and 35 for Failed
+pub unsafe fn exit_qemu_with(code: QemuExitCode) {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::<u32>::new(0xf4);
+    port.write(code as u32);
+}
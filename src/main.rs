@@ -5,17 +5,29 @@
 // silence certain warnings when testing is being performed
 #![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
 #![feature(asm)]
+#![feature(alloc)]
 
 #[macro_use]
 extern crate rust_os;
 extern crate x86_64;
 extern crate bootloader;
+extern crate alloc;
 
 use core::panic::PanicInfo;
-use rust_os::{gdt, interrupts};
-use rust_os::memory::{init, translate_addr, create_example_mapping, init_frame_allocator};
+use rust_os::{gdt, interrupts, allocator, backtrace, keyboard, serial};
+use rust_os::memory::{init, translate, translate_addr, map_contiguous, unmap_range,
+                      create_example_mapping, init_kernel_memory, out_of_memory, KERNEL_MEMORY};
+use rust_os::memory::vma::{self, Backing};
+use rust_os::memory::{inspect, protection, stats, ioremap, CacheMode};
+use rust_os::memory::address_space::{self, AddressSpace};
+use rust_os::memory::shared_memory::SharedMemory;
+use rust_os::memory::frame_allocator::init_bitmap_frame_allocator;
+use rust_os::memory::buddy_allocator::init_buddy_allocator;
 use bootloader::{bootinfo::BootInfo, entry_point};
-use x86_64::structures::paging::RecursivePageTable;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{RecursivePageTable, PageTableFlags, FrameAllocator, Page, PhysFrame, PageSize, Size2MiB};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 entry_point!(kernel_main);
 
@@ -28,18 +40,88 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     gdt::init();    // load GDT
     interrupts::init_idt();     // load IDT
 
-    // Initialize PICs for hardware interrupts
-    // unsafe: possible undefined behavior if PIC misconfigured
-    unsafe { interrupts::PICS.lock().initialize() };
-    x86_64::instructions::interrupts::enable();     // enables external interrupts
+    // physical memory layout as reported by the bootloader, for the host to check
+    serial_println!("boot_info p4_table_addr={:#018x}", boot_info.p4_table_addr);
+    serial_print!("{}", stats::MemoryMapReport(&boot_info.memory_map));
 
     let mut recursive_page_table: RecursivePageTable = unsafe { init(boot_info.p4_table_addr as usize) };
 
-
-    let mut frame_allocator = init_frame_allocator(&boot_info.memory_map);
+    // frames freed by unmapping pages are returned to this allocator and reused
+    let mut frame_allocator = init_bitmap_frame_allocator(&boot_info.memory_map);
 
     // create mapping at 0x1000
-    create_example_mapping(&mut recursive_page_table, &mut frame_allocator);
+    create_example_mapping(&mut recursive_page_table, &mut frame_allocator)
+        .unwrap_or_else(|err| out_of_memory("example mapping", err));
+
+    // map the kernel heap, alloc types can be used from here on
+    allocator::init_heap(&mut recursive_page_table, &mut frame_allocator)
+        .unwrap_or_else(|err| out_of_memory("heap", err));
+
+    // make kernel code read-only and everything else non executable. Mappings created
+    // from here on must set NO_EXECUTE themselves
+    let protected_pages = protection::enforce_wx(&mut recursive_page_table);
+    println!("W^X enforced, {} page(s) updated", protected_pages);
+
+    // pool of physically contiguous frames for huge pages and device buffers
+    let mut buddy_allocator = init_buddy_allocator(&mut frame_allocator)
+        .expect("buddy allocator initialization failed");
+
+    // map a 2MiB huge page to a block of the buddy allocator, which is aligned to its
+    // size. The whole block only takes a single level 2 entry, no level 1 table
+    let huge_page: Page<Size2MiB> = Page::containing_address(VirtAddr::new(0x_4000_0000_0000));
+    let huge_frame: PhysFrame<Size2MiB> = buddy_allocator.allocate_frame()
+        .expect("buddy allocation failed");
+    let huge_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe { map_contiguous(huge_page, huge_frame, 1, huge_flags, &mut recursive_page_table, &mut frame_allocator) }
+        .expect("huge page mapping failed");
+    vma::reserve("huge page example", huge_page.start_address(), Size2MiB::SIZE, huge_flags, Backing::Mapped)
+        .expect("huge page area reservation failed");
+    let huge_addr = huge_page.start_address() + 0x1234u64;
+    println!("{:?} -> {:?}", huge_addr, translate(huge_addr));
+
+    // This address is identity mapped for VGA and so the translation doesn't change the address
+    println!("0xb8000 -> {:?}", translate_addr(0xb8000));
+
+    // hand paging over to the kernel wide state so the page fault handler can map pages
+    // and kernel stacks can be allocated
+    init_kernel_memory(recursive_page_table, frame_allocator);
+    // kernel stacks can be allocated now, the double fault handler gets a guarded one
+    gdt::init_kernel_stacks();
+
+    #[cfg(feature = "map_physical_memory")]
+    {
+        use rust_os::memory::physical::{self, OffsetPageTable};
+
+        let mapped = physical::init(&boot_info.memory_map).expect("mapping physical memory failed");
+        println!("physical memory mapped at {:#x}: {:#x} bytes", physical::PHYSICAL_MEMORY_OFFSET, mapped);
+        // unsafe: the page tables aren't modified while the mapper exists
+        let offset_page_table = unsafe { OffsetPageTable::active() }.expect("physical memory not mapped");
+        println!("0xb8000 through the offset mapper -> {:?}",
+                 offset_page_table.translate(VirtAddr::new(0xb8000)));
+    }
+
+    // route hardware interrupts through the APICs if ACPI describes them, the PICs
+    // otherwise
+    let controller = interrupts::init_interrupt_controller();
+    println!("interrupt controller: {:?}", controller);
+    // drivers hook their IRQs, which unmasks them
+    keyboard::init().expect("keyboard interrupt registration failed");
+    serial::init_interrupts().expect("serial interrupt registration failed");
+    x86_64::instructions::interrupts::enable();     // enables external interrupts
+
+    // allocate a number and a growable list on the heap
+    let heap_value = Box::new(41);
+    println!("heap_value at {:p}", heap_value);
+    let mut vec = Vec::new();
+    for i in 0..500 {
+        vec.push(i);
+    }
+    println!("vec at {:p}", vec.as_slice());
+
+    let order = 4;
+    let block = buddy_allocator.allocate(order).expect("buddy allocation failed");
+    println!("{} contiguous frames at {:?}", 1 << order, block);
+    buddy_allocator.deallocate(block, order);
 
     // Write string New! to VGA buffer. Offsets by 900 since vga buffer pushes
     // top line off screen on next println
@@ -49,8 +131,92 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Writes to vga buffer with page mapped by frame allocator
     unsafe { (0xdeadbeaf900 as *mut u64).write_volatile(0xf021f077f065f04e)};
 
-    // This address is identity mapped for VGA and so the translation doesn't change the address
-    println!("0xb8000 -> {:?}", translate_addr(0xb8000, &recursive_page_table));
+    // reserve 1 MiB without using any physical memory. Each page is mapped to a zeroed
+    // frame by the page fault handler when it is first touched
+    let demand_start = VirtAddr::new(0x_5555_0000_0000);
+    let demand_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vma::reserve("demand paged example", demand_start, 1024 * 1024, demand_flags, Backing::OnDemand)
+        .expect("demand paged area reservation failed");
+    let demand_value = (demand_start + 0x8000u64).as_mut_ptr::<u64>();
+    unsafe { demand_value.write_volatile(42) };
+    println!("demand paged value: {}", unsafe { demand_value.read_volatile() });
+
+    // tear the area down again, only the touched page has a frame to return
+    let demand_area = vma::VIRTUAL_MEMORY_AREAS.lock().release(demand_start)
+        .expect("demand paged area not reserved");
+    let demand_pages = Page::range(Page::containing_address(demand_area.start),
+                                   Page::containing_address(demand_area.end));
+    let unmapped = KERNEL_MEMORY.lock().as_mut()
+        .map(|memory| unmap_range(demand_pages, &mut memory.page_table, &mut memory.frame_allocator))
+        .expect("kernel memory not initialized")
+        .expect("demand paged area unmapping failed");
+    println!("demand paged area released, {} page(s) freed", unmapped);
+
+    // create a second address space with a private user page and switch to it
+    let mut address_space = AddressSpace::new().expect("address space creation failed");
+    let user_page = Page::containing_address(VirtAddr::new(address_space::USER_START));
+    let user_flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    address_space.map_user_page(user_page, user_flags).expect("user page mapping failed");
+    println!("user page at {:?} -> {:?}", user_page.start_address(),
+             address_space.translate(user_page.start_address()));
+    let user_value = user_page.start_address().as_mut_ptr::<u64>();
+    unsafe {
+        address_space.activate();
+        user_value.write_volatile(7);
+    }
+
+    // the fork shares the page until the write below copies it
+    let mut child = address_space.fork().expect("address space fork failed");
+    unsafe {
+        user_value.write_volatile(8);
+        println!("user value: {}, after fork {:?} and {:?}", user_value.read_volatile(),
+                 address_space.translate(user_page.start_address()),
+                 child.translate(user_page.start_address()));
+        child.activate();
+        println!("user value in fork: {}", user_value.read_volatile());
+    }
+
+    // share a buffer between both address spaces without copying it
+    let buffer = SharedMemory::create("demo buffer", 4096).expect("shared memory creation failed");
+    let buffer_page = user_page + 16;
+    buffer.map(&mut address_space, buffer_page, user_flags).expect("shared memory mapping failed");
+    SharedMemory::open("demo buffer")
+        .and_then(|buffer| buffer.map(&mut child, buffer_page, user_flags))
+        .expect("shared memory mapping failed");
+    SharedMemory::unlink("demo buffer").expect("shared memory not found");
+    let buffer_value = buffer_page.start_address().as_mut_ptr::<u64>();
+    unsafe {
+        address_space.activate();
+        buffer_value.write_volatile(99);
+        child.activate();
+        println!("shared value in fork: {}", buffer_value.read_volatile());
+    }
+
+    address_space::activate_kernel();
+    drop(child);
+    drop(address_space);
+    // the buffer's frames are freed with the last handle, since no mapping is left
+    drop(buffer);
+
+    // map the registers of QEMU's HPET uncached and read the period of its counter. The
+    // VGA text buffer would be the obvious device, but it is identity mapped write-back
+    // already and mapping it with another cache mode would alias it
+    {
+        let hpet = PhysAddr::new(0xfed0_0000);
+        let registers = unsafe { ioremap::<[u64; 2]>(hpet, 0x400, CacheMode::Uncached) }
+            .expect("HPET mapping failed");
+        // the upper half of the capabilities register is the period in femtoseconds
+        let period = registers.read::<u64>(0) >> 32;
+        println!("HPET mapped at {:?} ({:?}): counter period {} fs", registers.virt_addr(),
+                 registers.mode(), period);
+    }
+
+    // print the resulting kernel address space layout and memory usage to the host
+    inspect::dump_mappings();
+    serial_print!("{}", stats::meminfo(&boot_info.memory_map));
+    // allocations still live at this point, e.g. leaked by the demos above
+    #[cfg(feature = "heap_debug")]
+    allocator::debug::dump_allocations();
 
     println!("It did not crash!");
     rust_os::hlt_loop();
@@ -61,5 +227,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    serial_println!("{}", info);
+    backtrace::print();
     rust_os::hlt_loop();
 }
@@ -1,9 +1,189 @@
 // in src/memory.rs
 
 //use x86_64::structures::paging::{Mapper, Page, PageTable, RecursivePageTable};
+use core::fmt;
 use x86_64::{VirtAddr, PhysAddr, structures::paging::*};
 //use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::instructions::tlb;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use memory::frame_allocator::BitmapFrameAllocator;
+use memory::vma::Backing;
+use memory::stats::PageTableAllocator;
+
+pub mod frame_allocator;
+pub mod buddy_allocator;
+pub mod vma;
+pub mod stack;
+pub mod page_fault;
+pub mod inspect;
+pub mod protection;
+pub mod stats;
+pub mod address_space;
+pub mod cow;
+pub mod shared_memory;
+pub mod physical;
+pub mod mmio;
+
+pub use self::mmio::{ioremap, CacheMode, Mmio};
+
+/// The kernel's page table together with the frame allocator used to grow it.
+///
+/// Kept together so that a single lock protects all paging modifications.
+pub struct KernelMemory {
+    // accesses the tables through the recursive entry, so it always modifies the
+    // active address space
+    pub page_table: RecursivePageTable<'static>,
+    pub frame_allocator: BitmapFrameAllocator,
+}
+
+/// Kernel page used to access frames that aren't mapped anywhere, e.g. frames of
+/// inactive address spaces.
+pub const SCRATCH_PAGE: u64 = 0x_5fff_ffff_f000;
+
+impl KernelMemory {
+    /// Maps the frame to the scratch page and passes its content to `f`. With the
+    /// `map_physical_memory` feature the linear mapping is used instead.
+    pub fn with_scratch_page<F, R>(&mut self, frame: PhysFrame, f: F) -> R
+        where F: FnOnce(&mut [u8]) -> R
+    {
+        // with all physical memory mapped, the frame is accessible without a new mapping
+        #[cfg(feature = "map_physical_memory")]
+        {
+            if let Some(addr) = physical::phys_to_virt(frame.start_address()) {
+                // unsafe: the frame is owned by the caller, who holds the kernel memory lock
+                return f(unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<u8>(),
+                                                                  Page::<Size4KiB>::SIZE as usize) });
+            }
+        }
+
+        let page: Page = Page::containing_address(VirtAddr::new(SCRATCH_PAGE));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        // unsafe: the scratch page is only used while the kernel memory is locked
+        let result = unsafe {
+            self.page_table.map_to(page, frame, flags, &mut PageTableAllocator(&mut self.frame_allocator))
+                .expect("scratch page mapping failed")
+                .flush();
+            f(core::slice::from_raw_parts_mut(page.start_address().as_mut_ptr::<u8>(),
+                                              Page::<Size4KiB>::SIZE as usize))
+        };
+        // the frame stays owned by the caller
+        self.page_table.unmap(page).expect("scratch page not mapped").1.flush();
+        result
+    }
+
+    /// Clears the frame, see `with_scratch_page`.
+    pub fn zero_frame(&mut self, frame: PhysFrame) {
+        self.with_scratch_page(frame, |bytes| {
+            for byte in bytes.iter_mut() {
+                *byte = 0;
+            }
+        });
+    }
+}
+
+// Available once init_kernel_memory has been called. Used by code that has no way of
+// being passed the page table, e.g. the page fault handler
+pub static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// Errors of the functions mapping and allocating memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// No free frame was left for the page or one of its page tables.
+    FrameAllocationFailed,
+    /// The page is already mapped.
+    PageAlreadyMapped,
+    /// A parent entry maps a huge page containing the page.
+    ParentEntryHugePage,
+    /// The page isn't mapped.
+    PageNotMapped,
+    /// A page table entry points to an invalid physical address.
+    InvalidFrameAddress(PhysAddr),
+    /// A virtual address range handed out in pieces, e.g. for stacks, is used up.
+    AddressRangeExhausted,
+    /// The heap has no free block for an allocation of the size and alignment.
+    HeapExhausted { size: usize, align: usize },
+    /// A stack of zero pages was requested.
+    EmptyStack,
+    /// A frame to share has the most owners the frame allocator can count.
+    TooManyOwners,
+}
+
+impl From<MapToError> for MemoryError {
+    fn from(err: MapToError) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => MemoryError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MemoryError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped => MemoryError::PageAlreadyMapped,
+        }
+    }
+}
+
+impl From<UnmapError> for MemoryError {
+    fn from(err: UnmapError) -> Self {
+        match err {
+            UnmapError::ParentEntryHugePage => MemoryError::ParentEntryHugePage,
+            UnmapError::PageNotMapped => MemoryError::PageNotMapped,
+            UnmapError::InvalidFrameAddress(addr) => MemoryError::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+impl From<FlagUpdateError> for MemoryError {
+    fn from(err: FlagUpdateError) -> Self {
+        match err {
+            FlagUpdateError::PageNotMapped => MemoryError::PageNotMapped,
+        }
+    }
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::FrameAllocationFailed => write!(f, "no free frames left"),
+            MemoryError::PageAlreadyMapped => write!(f, "page already mapped"),
+            MemoryError::ParentEntryHugePage => write!(f, "page is part of a huge page"),
+            MemoryError::PageNotMapped => write!(f, "page not mapped"),
+            MemoryError::InvalidFrameAddress(addr) => write!(f, "invalid frame address {:#x}", addr.as_u64()),
+            MemoryError::AddressRangeExhausted => write!(f, "virtual address range exhausted"),
+            MemoryError::HeapExhausted { size, align } =>
+                write!(f, "heap exhausted allocating {} bytes aligned to {}", size, align),
+            MemoryError::EmptyStack => write!(f, "stack of zero pages requested"),
+            MemoryError::TooManyOwners => write!(f, "too many owners of a shared frame"),
+        }
+    }
+}
+
+/// Reports that `subsystem` ran out of memory, then panics.
+///
+/// Prints a `out of memory` line with the subsystem and the error followed by the
+/// memory usage to serial first, so tests exhausting memory on purpose can check
+/// where it happened. Works while the kernel memory or the heap are locked.
+pub fn out_of_memory(subsystem: &str, error: MemoryError) -> ! {
+    serial_println!("out of memory subsystem={} error={:?}", subsystem, error);
+    serial_print!("{}", stats::try_usage());
+    panic!("out of memory in {}: {}", subsystem, error);
+}
+
+/// Hands the kernel's page table and frame allocator over to `KERNEL_MEMORY`.
+pub fn init_kernel_memory(page_table: RecursivePageTable<'static>,
+                          frame_allocator: BitmapFrameAllocator) {
+    *KERNEL_MEMORY.lock() = Some(KernelMemory { page_table, frame_allocator });
+
+    // stacks are mapped by the stack allocator, their guard pages are never mapped
+    let data_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vma::reserve("kernel stacks", VirtAddr::new(stack::KERNEL_STACKS_START),
+                 stack::KERNEL_STACKS_SIZE, data_flags, Backing::Mapped)
+        .expect("kernel stack region overlaps a reserved area");
+    vma::reserve("scratch page", VirtAddr::new(SCRATCH_PAGE), Page::<Size4KiB>::SIZE,
+                 data_flags, Backing::Mapped)
+        .expect("scratch page overlaps a reserved area");
+
+    // the address spaces share the P4 entries of the areas reserved so far
+    mmio::init();
+    address_space::init();
+}
 
 /// A FrameAllocator that always returns `None`.
 pub struct EmptyFrameAllocator;
@@ -69,7 +249,7 @@ pub fn create_example_mapping(
     // The Size4KiB argument in the trait implementation is needed because the Page and PhysFrame
     // types are generic over the PageSize trait to work with both standard 4KiB pages and huge 2MiB/1GiB pages.
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
+) -> Result<(), MemoryError> {
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     // Bootloader occupies first MB of virtual memory, so know level 1 page table
@@ -89,11 +269,12 @@ pub fn create_example_mapping(
         // at 0xb8000. Unsafe because possible to break memory safety with invalid arguments.
         // frame_allocator must implement FrameAllocator trait.The map_to method needs this argument
         // because it might need unused frames for creating new page tables.
-        recursive_page_table.map_to(page, frame, flags, frame_allocator)
+        recursive_page_table.map_to(page, frame, flags, &mut PageTableAllocator(frame_allocator))
     };
-    // Sample code so use expect to panic in case of error.
+    // Errors are passed on, e.g. when no frame is left for the new page tables.
     // Return MapperFlush type provides easy way to flush newly mapped page from TLB
-    map_to_result.expect("map_to failed").flush();
+    map_to_result?.flush();
+    Ok(())
 }
 
 /// Creates a RecursivePageTable instance from the level 4 address.
@@ -114,15 +295,378 @@ pub unsafe fn init(level_4_table_addr: usize) -> RecursivePageTable<'static> {
     init_inner(level_4_table_addr)
 }
 
+/// Result of translating a virtual address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys_addr: PhysAddr,
+    // size of the page containing the address: 4KiB, 2MiB or 1GiB
+    pub page_size: u64,
+}
+
+impl Translation {
+    // Returns the translation of the address the walk was made for
+    pub(crate) fn from_walk(addr: VirtAddr, walk: &page_fault::PageWalk) -> Option<Translation> {
+        if !walk.is_mapped() {
+            return None;
+        }
+        let leaf = walk.steps().last().expect("walk without steps");
+        let page_size = inspect::level_size(leaf.level);
+        let offset = addr.as_u64() & (page_size - 1);
+        Some(Translation { phys_addr: leaf.entry.addr() + offset, page_size })
+    }
+}
+
+/// Translates the virtual address, following huge page entries at P3 and P2.
+///
+/// Returns `None` if the virtual address is not mapped. Reads the active page tables
+/// through the recursive entry set up by the bootloader.
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    // RecursivePageTable::translate_page can't be used since it expects the page size
+    // to be known: for a 4KiB page inside a huge page it reads the huge page's memory
+    // as page table
+    // unsafe: the recursive entry is set up before the kernel is entered
+    let walk = unsafe { page_fault::PageWalk::new(addr) };
+    Translation::from_walk(addr, &walk)
+}
+
 /// Returns the physical address for the given virtual address, or `None` if
 /// the virtual address is not mapped.
-pub fn translate_addr(addr: u64, recursive_page_table: &RecursivePageTable)
-                      -> Option<PhysAddr>
+pub fn translate_addr(addr: u64) -> Option<PhysAddr> {
+    translate(VirtAddr::new(addr))
+        .map(|translation| translation.phys_addr)
+}
+
+/// Maps `count` consecutive pages of size `S` starting at `page` to the consecutive
+/// frames starting at `frame`. With 2MiB or 1GiB pages no level 1 (and level 2)
+/// tables are needed.
+///
+/// Pages mapped before an error occurred are unmapped again.
+///
+/// Unsafe: the caller must ensure the frames aren't aliased by other mappings in
+/// a way that breaks memory safety.
+pub unsafe fn map_contiguous<S, M, A>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    count: u64,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<(), MemoryError>
+    where S: PageSize, M: Mapper<S>, A: FrameAllocator<Size4KiB>
+{
+    for i in 0..count {
+        let result = mapper.map_to(page + i, frame + i, flags, &mut PageTableAllocator(frame_allocator));
+        match result {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                for mapped in Page::range(page, page + i) {
+                    mapper.unmap(mapped).expect("page mapped above is missing").1.flush();
+                }
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Identity maps the physical range `start..start + size` with pages of size `S`.
+///
+/// The range is extended to page boundaries. Unsafe for the same reasons as
+/// `map_contiguous`.
+pub unsafe fn identity_map_range<S, M, A>(
+    start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<(), MemoryError>
+    where S: PageSize, M: Mapper<S>, A: FrameAllocator<Size4KiB>
+{
+    let first_frame: PhysFrame<S> = PhysFrame::containing_address(start);
+    let end = start.as_u64() + size;
+    let count = (end - first_frame.start_address().as_u64() + S::SIZE - 1) / S::SIZE;
+    let first_page = Page::containing_address(VirtAddr::new(first_frame.start_address().as_u64()));
+    map_contiguous(first_page, first_frame, count, flags, mapper, frame_allocator)
+}
+
+// Ranges with more pages than this flush the whole TLB once instead of every page,
+// which is cheaper than invalidating the pages one by one
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+// Flushes a single page, or nothing if the whole TLB is flushed at the end
+fn flush_page<S: PageSize>(flush: MapperFlush<S>, flush_all: bool) {
+    if flush_all {
+        flush.ignore();
+    } else {
+        flush.flush();
+    }
+}
+
+/// Unmaps all mapped pages in the range and returns their frames to the frame
+/// allocator. Pages that aren't mapped are skipped, so partially touched `OnDemand`
+/// areas can be torn down.
+///
+/// The frames must be owned by the mapping, e.g. not MMIO or shared frames.
+/// Returns the number of unmapped pages.
+pub fn unmap_range<S, M, A>(
+    pages: PageRange<S>,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<u64, MemoryError>
+    where S: PageSize, M: Mapper<S>, A: FrameDeallocator<S>
+{
+    let flush_all = pages.end - pages.start > FLUSH_ALL_THRESHOLD;
+    let mut unmapped = 0;
+    for page in pages {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush_page(flush, flush_all);
+                frame_allocator.deallocate_frame(frame);
+                unmapped += 1;
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(err) => {
+                if flush_all {
+                    tlb::flush_all();
+                }
+                return Err(err.into());
+            }
+        }
+    }
+    if flush_all {
+        tlb::flush_all();
+    }
+    Ok(unmapped)
+}
+
+/// Moves the mappings of the range to the range starting at `new_start`, mapping
+/// the same frames with `flags`. Pages that aren't mapped are skipped.
+///
+/// The ranges must not overlap. If an error occurs, the pages before the failing one
+/// are already moved and the others are still mapped at their old address. Returns
+/// `ParentEntryHugePage` without moving anything if a page of the range is part of a
+/// larger page, see `check_page_sizes`.
+///
+/// Unsafe: all references into the old range become invalid. The mapper must edit the
+/// active page tables.
+pub unsafe fn remap_range<S, M, A>(
+    pages: PageRange<S>,
+    new_start: Page<S>,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<(), MemoryError>
+    where S: PageSize, M: Mapper<S>, A: FrameAllocator<Size4KiB>
+{
+    let count = pages.end - pages.start;
+    assert!(new_start + count <= pages.start || pages.end <= new_start, "remap ranges overlap");
+    check_page_sizes(pages, translate)?;
+
+    let flush_all = count > FLUSH_ALL_THRESHOLD;
+    let mut result = Ok(());
+    for (i, page) in pages.enumerate() {
+        let frame = match mapper.translate_page(page) {
+            Some(frame) => frame,
+            None => continue,
+        };
+        // map the new page first so nothing is lost if the mapping fails
+        match mapper.map_to(new_start + i as u64, frame, flags, &mut PageTableAllocator(frame_allocator)) {
+            Ok(flush) => flush.ignore(),    // the new page wasn't mapped before
+            Err(err) => {
+                result = Err(err.into());
+                break;
+            }
+        }
+        let (_, flush) = mapper.unmap(page).expect("translated page is not mapped");
+        flush_page(flush, flush_all);
+    }
+    if flush_all {
+        tlb::flush_all();
+    }
+    result
+}
+
+/// Changes the flags of all mapped pages in the range, e.g. to make them read-only
+/// with WRITABLE removed or non executable with NO_EXECUTE. Pages that aren't
+/// mapped are skipped.
+///
+/// Returns the number of updated pages, or `ParentEntryHugePage` without updating any
+/// page if a page of the range is part of a larger page, see `check_page_sizes`. The
+/// mapper must edit the active page tables.
+pub fn protect_range<S, M>(
+    pages: PageRange<S>,
+    flags: PageTableFlags,
+    mapper: &mut M,
+) -> Result<u64, MemoryError>
+    where S: PageSize, M: Mapper<S>
+{
+    check_page_sizes(pages, translate)?;
+
+    let flush_all = pages.end - pages.start > FLUSH_ALL_THRESHOLD;
+    let mut updated = 0;
+    for page in pages {
+        // the only possible error is PageNotMapped
+        if let Ok(flush) = mapper.update_flags(page, flags | PageTableFlags::PRESENT) {
+            flush_page(flush, flush_all);
+            updated += 1;
+        }
+    }
+    if flush_all {
+        tlb::flush_all();
+    }
+    Ok(updated)
+}
+
+// Checks that no mapped page of the range is part of a larger page, translating with
+// `translate`. The Mapper implementations of the x86_64 crate don't look for huge page
+// entries above the level of the page size they are used with: for a 4KiB page inside
+// a 2MiB page they would use the huge page's memory as P1 table and write into it
+fn check_page_sizes<S, F>(pages: PageRange<S>, translate: F) -> Result<(), MemoryError>
+    where S: PageSize, F: Fn(VirtAddr) -> Option<Translation>
+{
+    for page in pages {
+        match translate(page.start_address()) {
+            Some(translation) if translation.page_size > S::SIZE => {
+                return Err(MemoryError::ParentEntryHugePage);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Maps the page to a newly allocated frame that is cleared to zero.
+///
+/// The frame is returned to the allocator if the page can't be mapped.
+pub fn map_zeroed_page<A>(
+    page: Page,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut A,
+) -> Result<(), MemoryError>
+    where A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>
 {
-    let addr = VirtAddr::new(addr);
-    let page: Page = Page::containing_address(addr);
+    let frame = frame_allocator.allocate_frame().ok_or(MemoryError::FrameAllocationFailed)?;
+
+    // map writable at first so the frame can be cleared through the page
+    let writable_flags = flags | PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    // unsafe: callers only pass pages of reserved areas, so no other memory is aliased
+    let map_to_result = unsafe {
+        mapper.map_to(page, frame, writable_flags, &mut PageTableAllocator(frame_allocator))
+    };
+    match map_to_result {
+        Ok(flush) => flush.flush(),
+        Err(err) => {
+            frame_allocator.deallocate_frame(frame);
+            return Err(err.into());
+        }
+    }
+
+    // unsafe: the page was just mapped to an otherwise unused frame
+    unsafe { core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, Page::<Size4KiB>::SIZE as usize) };
+
+    if !flags.contains(PageTableFlags::WRITABLE) {
+        mapper.update_flags(page, flags | PageTableFlags::PRESENT)
+            .expect("page mapped above is missing")
+            .flush();
+    }
+    Ok(())
+}
+
+/// Tries to resolve a page fault by mapping the faulting page of an `OnDemand` area,
+/// or by copying a copy-on-write page that is written to.
+///
+/// Returns `true` if the page was mapped and the faulting instruction can be
+/// retried, `false` if the access is invalid and the fault is fatal.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // protection violations happen on present pages, mapping a new frame can't fix them.
+    // Only writes to copy-on-write pages are resolved by giving the page its own frame
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            return false;
+        }
+        return match KERNEL_MEMORY.try_lock() {
+            Some(mut kernel_memory) => match *kernel_memory {
+                Some(ref mut memory) => cow::handle_write_fault(addr, memory),
+                None => false,
+            },
+            None => false,
+        };
+    }
 
-    // perform the translation
-    let frame = recursive_page_table.translate_page(page);
-    frame.map(|frame| frame.start_address() + u64::from(addr.page_offset()))
-}
\ No newline at end of file
+    // try_lock: the fault may have happened while the lock was held, waiting for it would deadlock
+    let area = match vma::VIRTUAL_MEMORY_AREAS.try_lock() {
+        Some(areas) => areas.find(addr),
+        None => None,
+    };
+    let area = match area {
+        Some(ref area) if area.backing == Backing::OnDemand => *area,
+        _ => return false,
+    };
+
+    // the access itself must be allowed by the area
+    if (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !area.flags.contains(PageTableFlags::WRITABLE))
+        || (error_code.contains(PageFaultErrorCode::USER_MODE)
+            && !area.flags.contains(PageTableFlags::USER_ACCESSIBLE))
+        || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && area.flags.contains(PageTableFlags::NO_EXECUTE)) {
+        return false;
+    }
+
+    let mut kernel_memory = match KERNEL_MEMORY.try_lock() {
+        Some(kernel_memory) => kernel_memory,
+        None => return false,
+    };
+    match *kernel_memory {
+        Some(ref mut memory) => {
+            let page = Page::containing_address(addr);
+            match map_zeroed_page(page, area.flags, &mut memory.page_table, &mut memory.frame_allocator) {
+                Ok(()) => true,
+                // the faulting code can't be told, so there is no way to continue
+                Err(MemoryError::FrameAllocationFailed) =>
+                    out_of_memory("demand paging", MemoryError::FrameAllocationFailed),
+                Err(_) => false,
+            }
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::boxed::Box;
+    use memory::page_fault::PageWalk;
+
+    #[test]
+    fn rejects_pages_inside_huge_pages() {
+        let huge_start = VirtAddr::new(0x4000_0000_0000);
+        let mut p4 = Box::new(PageTable::new());
+        let mut p3 = Box::new(PageTable::new());
+        let mut p2 = Box::new(PageTable::new());
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        p4[huge_start.p4_index()].set_addr(PhysAddr::new(0x1000), flags);
+        p3[huge_start.p3_index()].set_addr(PhysAddr::new(0x2000), flags);
+        p2[huge_start.p2_index()].set_addr(PhysAddr::new(0x20_0000), flags | PageTableFlags::HUGE_PAGE);
+        let translate = |addr: VirtAddr| {
+            let walk = PageWalk::walk_with(addr, |level| match level {
+                4 => &p4,
+                3 => &p3,
+                2 => &p2,
+                _ => panic!("P1 of a huge page accessed"),
+            });
+            Translation::from_walk(addr, &walk)
+        };
+
+        // 4KiB pages in the middle of the 2MiB page
+        let start: Page = Page::containing_address(huge_start + 0x1000u64);
+        assert_eq!(check_page_sizes(Page::range(start, start + 4), &translate),
+                   Err(MemoryError::ParentEntryHugePage));
+        // the 2MiB page itself, and unmapped 4KiB pages after it
+        let huge: Page<Size2MiB> = Page::containing_address(huge_start);
+        assert_eq!(check_page_sizes(Page::range(huge, huge + 1), &translate), Ok(()));
+        let after: Page = Page::containing_address(huge_start + Size2MiB::SIZE);
+        assert_eq!(check_page_sizes(Page::range(after, after + 4), &translate), Ok(()));
+    }
+}
@@ -0,0 +1,509 @@
+use core::ops::Range;
+use core::sync::atomic::Ordering;
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page,
+                                 PageRange, PageTable, PageTableEntry, PageTableFlags, PhysFrame, RecursivePageTable,
+                                 Size4KiB};
+use memory::{self, cow, KernelMemory, MemoryError, KERNEL_MEMORY};
+use memory::mmio::PAT_4KIB;
+use memory::frame_allocator::BitmapFrameAllocator;
+use memory::page_fault::{recursive_table_address, RECURSIVE_INDEX};
+use memory::stats::{self, PageTableAllocator};
+use memory::vma::{self, Backing};
+
+/// Start of the user part of every address space (P4 index 32).
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+/// End of the user part, exclusive (P4 index 128).
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+
+// P4 entry of the active table that points to an inactive P4 table while it is
+// modified. The inactive table points to itself at this index as well, so its tables
+// are reachable just like the active ones through the recursive entry
+const TEMPORARY_INDEX: u64 = 510;
+
+// P4 frame of the address space set up by the bootloader
+static KERNEL_P4: Once<PhysFrame> = Once::new();
+
+/// Remembers the active address space as the kernel's, creates the P3 tables of the
+/// kernel's areas and reserves the user part in the kernel's virtual memory areas.
+///
+/// The P3 tables are created for the areas reserved so far and for the physical memory
+/// mapping. Kernel mappings made later below other P4 entries are only visible in the
+/// address spaces created afterwards.
+///
+/// Called by `memory::init_kernel_memory`, once its areas are reserved.
+pub fn init() {
+    KERNEL_P4.call_once(|| Cr3::read().0);
+
+    // P4 entries covering the kernel's areas. The physical memory mapping is reserved
+    // later, but always at the same place
+    let mut kernel_entries = [false; 512];
+    for area in vma::VIRTUAL_MEMORY_AREAS.lock().iter() {
+        for index in p4_indices(area.start, area.end) {
+            kernel_entries[index as usize] = true;
+        }
+    }
+    #[cfg(feature = "map_physical_memory")]
+    {
+        use memory::physical::{MAX_MAPPED_MEMORY, PHYSICAL_MEMORY_OFFSET};
+        let start = VirtAddr::new(PHYSICAL_MEMORY_OFFSET);
+        for index in p4_indices(start, start + MAX_MAPPED_MEMORY) {
+            kernel_entries[index as usize] = true;
+        }
+    }
+
+    // Address spaces copy the kernel's P4 entries when they are created. With a table
+    // behind the entries of the kernel's areas they never change afterwards, so kernel
+    // mappings made later, in whichever address space is active, are visible in all
+    // of them
+    {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for index in 0..512 {
+            if !kernel_entries[index as usize] || is_user_p4_index(index)
+                || index == TEMPORARY_INDEX || index == RECURSIVE_INDEX {
+                continue;
+            }
+            // unsafe: the active P4 table always exists. It is looked up again for
+            // every entry, since zeroing a frame may map the scratch page
+            if !unsafe { table(RECURSIVE_INDEX, &[]) }[index as usize].is_unused() {
+                continue;
+            }
+            let p3_frame = memory.frame_allocator.allocate_frame()
+                .unwrap_or_else(|| memory::out_of_memory("kernel page tables", MemoryError::FrameAllocationFailed));
+            memory.zero_frame(p3_frame);
+            // unsafe: the entry was unused, so no translation changes
+            let p4 = unsafe { table(RECURSIVE_INDEX, &[]) };
+            p4[index as usize].set_frame(p3_frame, flags);
+            stats::PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // keeps the kernel from placing anything where address spaces map user pages
+    let user_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    vma::reserve("user space", VirtAddr::new(USER_START), USER_END - USER_START, user_flags,
+                 Backing::Mapped)
+        .expect("user space overlaps a reserved area");
+}
+
+/// Switches back to the kernel's address space set up by the bootloader.
+pub fn activate_kernel() {
+    let p4_frame = *KERNEL_P4.try().expect("address spaces not initialized");
+    // unsafe: the kernel's tables are never freed
+    unsafe { Cr3::write(p4_frame, Cr3Flags::empty()) };
+}
+
+fn is_user_p4_index(index: u64) -> bool {
+    USER_START >> 39 <= index && index < USER_END >> 39
+}
+
+// Returns the P4 indices used to translate the addresses from `start` to `end`, exclusive
+fn p4_indices(start: VirtAddr, end: VirtAddr) -> Range<u64> {
+    let index = |addr: u64| (addr >> 39) & 0o777;
+    index(start.as_u64())..index(end.as_u64() - 1) + 1
+}
+
+// Returns the table reached by following the P4, P3 and P2 `indices` from the P4
+// table, accessed through the recursive entry at `recursive_index`.
+// Unsafe: the table must exist and not be referenced elsewhere
+unsafe fn table<'a>(recursive_index: u64, indices: &[u64]) -> &'a mut PageTable {
+    let mut addr = 0;
+    for (i, &index) in indices.iter().enumerate() {
+        addr |= index << (39 - 9 * i);
+    }
+    let level = 4 - indices.len() as u8;
+    let table_addr = recursive_table_address(VirtAddr::new_unchecked(addr), level, recursive_index);
+    &mut *table_addr.as_mut_ptr::<PageTable>()
+}
+
+// Adds USER_ACCESSIBLE to the P4, P3 and P2 entries used to translate the address.
+// map_to creates the parent tables for kernel access only, but the CPU checks it on
+// every level. The user part's tables aren't shared with the kernel, so it can simply
+// be added.
+// Unsafe: the parent tables must exist
+unsafe fn allow_user_access(addr: VirtAddr, recursive_index: u64) {
+    for level in 2..5 {
+        let table = &mut *recursive_table_address(addr, level, recursive_index).as_mut_ptr::<PageTable>();
+        let index = (addr.as_u64() >> (12 + 9 * u64::from(level - 1))) & 0o777;
+        let entry = &mut table[index as usize];
+        let entry_flags = entry.flags();
+        entry.set_flags(entry_flags | PageTableFlags::USER_ACCESSIBLE);
+    }
+}
+
+// Maps every user page of the active address space into the attached one with `mapper`,
+// sharing the frames copy-on-write. Fails with ParentEntryHugePage on huge pages, which
+// address spaces don't map.
+// Unsafe: the mapper must belong to the address space attached at `recursive_index`
+unsafe fn share_user_pages(mapper: &mut RecursivePageTable, frame_allocator: &mut BitmapFrameAllocator,
+                           recursive_index: u64) -> Result<(), MemoryError> {
+    let p4 = table(RECURSIVE_INDEX, &[]);
+    for i4 in (USER_START >> 39)..(USER_END >> 39) {
+        if table_frame(&p4[i4 as usize])?.is_none() {
+            continue;
+        }
+        let p3 = table(RECURSIVE_INDEX, &[i4]);
+        for i3 in 0..512 {
+            if table_frame(&p3[i3 as usize])?.is_none() {
+                continue;
+            }
+            let p2 = table(RECURSIVE_INDEX, &[i4, i3]);
+            for i2 in 0..512 {
+                if table_frame(&p2[i2 as usize])?.is_none() {
+                    continue;
+                }
+                let p1 = table(RECURSIVE_INDEX, &[i4, i3, i2]);
+                for i1 in 0..512 {
+                    let entry = &mut p1[i1 as usize];
+                    if !entry.flags().contains(PageTableFlags::PRESENT) {
+                        continue;
+                    }
+                    // bit 7 is the PAT bit here, which frame() mistakes for a huge page
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    frame_allocator.share_frame(frame)?;
+                    let flags = cow::shared_flags(entry.flags());
+                    entry.set_flags(flags);
+
+                    let addr = VirtAddr::new(i4 << 39 | i3 << 30 | i2 << 21 | i1 << 12);
+                    let page = Page::containing_address(addr);
+                    // map_to rejects the PAT bit, so it is set afterwards. The attached
+                    // address space isn't in the TLB
+                    let result = mapper
+                        .map_to(page, frame, flags - PAT_4KIB, &mut PageTableAllocator(frame_allocator))
+                        .map(|flush| flush.ignore());
+                    if let Err(err) = result {
+                        frame_allocator.deallocate_frame(frame);
+                        return Err(err.into());
+                    }
+                    if flags.contains(PAT_4KIB) {
+                        mapper.update_flags(page, flags).expect("page mapped above is missing").ignore();
+                    }
+                    allow_user_access(addr, recursive_index);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An address space with its own P4 table.
+///
+/// The kernel's P4 entries are copied on creation, so the kernel's mappings below them
+/// are shared by all address spaces. The entries of the kernel's areas point to tables
+/// created by `init`, so the copies stay valid. The user part `USER_START..USER_END` is private to each
+/// address space.
+///
+/// Methods take the `KERNEL_MEMORY` lock, so they must not be called while it is held.
+pub struct AddressSpace {
+    p4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel's mappings and an empty user part.
+    pub fn new() -> Result<AddressSpace, MemoryError> {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+
+        let p4_frame = memory.frame_allocator.allocate_frame()
+            .ok_or(MemoryError::FrameAllocationFailed)?;
+        memory.zero_frame(p4_frame);
+        stats::PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        let address_space = AddressSpace { p4_frame };
+
+        // unsafe: the new table is only reachable through the temporary entry
+        unsafe {
+            let active_p4 = table(RECURSIVE_INDEX, &[]);
+            let p4 = address_space.attach();
+            for index in 0..512 {
+                if !is_user_p4_index(index) && index != TEMPORARY_INDEX && index != RECURSIVE_INDEX {
+                    p4[index as usize] = active_p4[index as usize].clone();
+                }
+            }
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            p4[RECURSIVE_INDEX as usize].set_frame(p4_frame, flags);
+            address_space.detach();
+        }
+        Ok(address_space)
+    }
+
+    /// Returns whether the address space is the active one.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4_frame
+    }
+
+    /// Switches to this address space by loading its P4 table into CR3.
+    ///
+    /// Unsafe: the address space must not be dropped while it is active, and the
+    /// current stack must be a kernel stack, which is the case for all stacks so far.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.p4_frame, Cr3Flags::empty());
+    }
+
+    /// Maps the user page to a newly allocated zeroed frame and returns the frame.
+    ///
+    /// USER_ACCESSIBLE is added to the flags.
+    pub fn map_user_page(&mut self, page: Page, flags: PageTableFlags)
+                         -> Result<PhysFrame, MemoryError> {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+
+        let frame = memory.frame_allocator.allocate_frame()
+            .ok_or(MemoryError::FrameAllocationFailed)?;
+        memory.zero_frame(frame);
+        match self.map_user_frame_locked(memory, page, frame, flags) {
+            Ok(()) => Ok(frame),
+            Err(err) => {
+                memory.frame_allocator.deallocate_frame(frame);
+                Err(err)
+            }
+        }
+    }
+
+    /// Maps the user page to the given frame, adding USER_ACCESSIBLE to the flags.
+    ///
+    /// Unsafe: the frame will be accessible to user code, so it must not contain
+    /// kernel data. It is returned to the frame allocator when the page is unmapped.
+    pub unsafe fn map_user_frame(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags)
+                                 -> Result<(), MemoryError> {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        self.map_user_frame_locked(memory, page, frame, flags)
+    }
+
+    fn map_user_frame_locked(&mut self, memory: &mut KernelMemory, page: Page, frame: PhysFrame,
+                             flags: PageTableFlags) -> Result<(), MemoryError> {
+        let addr = page.start_address();
+        assert!(is_user_p4_index(u64::from(addr.p4_index())), "{:?} is not a user page", page);
+
+        self.edit(memory, |mapper, frame_allocator, recursive_index| {
+            let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            // unsafe: user pages are private to this address space
+            unsafe {
+                mapper.map_to(page, frame, flags, &mut PageTableAllocator(frame_allocator))?.flush();
+            }
+
+            // unsafe: the parent tables were just created by map_to
+            unsafe { allow_user_access(addr, recursive_index) };
+            Ok(())
+        })
+    }
+
+    /// Creates a copy of this address space, which must be the active one, without
+    /// copying any memory.
+    ///
+    /// Both address spaces share the frames of the user part. Writable pages become
+    /// read-only copy-on-write pages in both, and get a copy of their frame on the first
+    /// write. This is the basis of `fork`.
+    pub fn fork(&self) -> Result<AddressSpace, MemoryError> {
+        assert!(self.is_active(), "only the active address space can be forked");
+        let child = AddressSpace::new()?;
+
+        let result = {
+            let mut kernel_memory = KERNEL_MEMORY.lock();
+            let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+            let result = child.edit(memory, |mapper, frame_allocator, recursive_index| {
+                // unsafe: this address space is active, so its tables are reachable through
+                // the recursive entry while the child's are attached
+                unsafe { share_user_pages(mapper, frame_allocator, recursive_index) }
+            });
+            // the pages of this address space lost WRITABLE
+            tlb::flush_all();
+            result
+        };
+        // on errors the child is dropped after the lock is released, freeing its part
+        result.map(|()| child)
+    }
+
+    /// Maps consecutive user pages starting at `start` to the frames, which are shared
+    /// with their current owner.
+    ///
+    /// Each mapping holds a reference to its frame, so the frames stay allocated until
+    /// both the owner and the mappings are gone. Nothing is mapped on errors.
+    pub fn map_shared_frames(&mut self, start: Page, frames: &[PhysFrame], flags: PageTableFlags)
+                             -> Result<(), MemoryError> {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+
+        for (i, &frame) in frames.iter().enumerate() {
+            let page = start + i as u64;
+            let result = match memory.frame_allocator.share_frame(frame) {
+                Ok(()) => {
+                    let result = self.map_user_frame_locked(memory, page, frame, flags);
+                    if result.is_err() {
+                        // drops the reference taken above
+                        memory.frame_allocator.deallocate_frame(frame);
+                    }
+                    result
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                self.unmap_user_range_locked(memory, Page::range(start, page))
+                    .expect("unmapping the shared frames mapped so far failed");
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the user pages and drops their references to the frames. Pages that
+    /// aren't mapped are skipped.
+    pub fn unmap_user_range(&mut self, pages: PageRange) -> Result<(), MemoryError> {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        self.unmap_user_range_locked(memory, pages)
+    }
+
+    fn unmap_user_range_locked(&mut self, memory: &mut KernelMemory, pages: PageRange)
+                               -> Result<(), MemoryError> {
+        let is_user_page = |page: Page| is_user_p4_index(u64::from(page.start_address().p4_index()));
+        assert!(pages.start >= pages.end || (is_user_page(pages.start) && is_user_page(pages.end - 1)),
+                "{:?} are not user pages", pages);
+        self.edit(memory, |mapper, frame_allocator, _| {
+            memory::unmap_range(pages, mapper, frame_allocator).map(|_| ())
+        })
+    }
+
+    /// Unmaps the user page and returns its frame to the frame allocator.
+    pub fn unmap_user_page(&mut self, page: Page) -> Result<(), MemoryError> {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        self.edit(memory, |mapper, frame_allocator, _| {
+            let (frame, flush) = mapper.unmap(page)?;
+            flush.flush();
+            frame_allocator.deallocate_frame(frame);
+            Ok(())
+        })
+    }
+
+    /// Translates an address of this address space, which doesn't need to be active.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        self.edit(memory, |mapper, _, _| {
+            let frame = mapper.translate_page(Page::<Size4KiB>::containing_address(addr))?;
+            Some(frame.start_address() + u64::from(addr.page_offset()))
+        })
+    }
+
+    // Runs `f` with a mapper for this address space, the frame allocator and the index
+    // of the recursive entry through which the address space's tables are reachable
+    fn edit<F, R>(&self, memory: &mut KernelMemory, f: F) -> R
+        where F: FnOnce(&mut RecursivePageTable, &mut BitmapFrameAllocator, u64) -> R
+    {
+        if self.is_active() {
+            return f(&mut memory.page_table, &mut memory.frame_allocator, RECURSIVE_INDEX);
+        }
+
+        // unsafe: the tables of an inactive address space are only accessed here, while
+        // the kernel memory is locked
+        unsafe {
+            self.attach();
+            let p4 = table(TEMPORARY_INDEX, &[]);
+            // p4_index returns the index in the type new_unchecked expects
+            let recursive_index = VirtAddr::new_unchecked(TEMPORARY_INDEX << 39).p4_index();
+            let mut mapper = RecursivePageTable::new_unchecked(p4, recursive_index);
+            let result = f(&mut mapper, &mut memory.frame_allocator, TEMPORARY_INDEX);
+            self.detach();
+            result
+        }
+    }
+
+    // Makes the inactive P4 table reachable through the temporary entry of the active
+    // one and returns it.
+    // Unsafe: no other address space may be attached
+    unsafe fn attach(&self) -> &'static mut PageTable {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let active_p4 = table(RECURSIVE_INDEX, &[]);
+        assert!(active_p4[TEMPORARY_INDEX as usize].is_unused(), "an address space is already attached");
+        active_p4[TEMPORARY_INDEX as usize].set_frame(self.p4_frame, flags);
+        tlb::flush_all();
+
+        let p4 = table(RECURSIVE_INDEX, &[TEMPORARY_INDEX]);
+        p4[TEMPORARY_INDEX as usize].set_frame(self.p4_frame, flags);
+        tlb::flush_all();
+        p4
+    }
+
+    // Removes the temporary entries again
+    unsafe fn detach(&self) {
+        table(RECURSIVE_INDEX, &[TEMPORARY_INDEX])[TEMPORARY_INDEX as usize].set_unused();
+        table(RECURSIVE_INDEX, &[])[TEMPORARY_INDEX as usize].set_unused();
+        tlb::flush_all();
+    }
+}
+
+// Returns the frame of the table a P4, P3 or P2 entry points to, `None` if it is
+// unused. Fails for huge pages, which address spaces don't map: they only map 4KiB user
+// pages themselves
+fn table_frame(entry: &PageTableEntry) -> Result<Option<PhysFrame>, MemoryError> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        Ok(None)
+    } else if flags.contains(PageTableFlags::HUGE_PAGE) {
+        Err(MemoryError::ParentEntryHugePage)
+    } else {
+        Ok(Some(PhysFrame::containing_address(entry.addr())))
+    }
+}
+
+// Frees all tables and frames of the user part, then the P4 table
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropped the active address space");
+
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        self.edit(memory, |_, frame_allocator, recursive_index| {
+            let mut table_frames = 0;
+            // unsafe: the tables of the user part belong to this address space only
+            unsafe {
+                let p4 = table(recursive_index, &[]);
+                for i4 in (USER_START >> 39)..(USER_END >> 39) {
+                    let p3_frame = match table_frame(&p4[i4 as usize]) {
+                        Ok(Some(frame)) => frame,
+                        // huge pages belong to whoever mapped them and aren't freed
+                        _ => continue,
+                    };
+                    let p3 = table(recursive_index, &[i4]);
+                    for i3 in 0..512 {
+                        let p2_frame = match table_frame(&p3[i3 as usize]) {
+                            Ok(Some(frame)) => frame,
+                            _ => continue,
+                        };
+                        let p2 = table(recursive_index, &[i4, i3]);
+                        for i2 in 0..512 {
+                            let p1_frame = match table_frame(&p2[i2 as usize]) {
+                                Ok(Some(frame)) => frame,
+                                _ => continue,
+                            };
+                            let p1 = table(recursive_index, &[i4, i3, i2]);
+                            for i1 in 0..512 {
+                                // bit 7 is the PAT bit here, which frame() would take
+                                // for a huge page
+                                if p1[i1].flags().contains(PageTableFlags::PRESENT) {
+                                    frame_allocator.deallocate_frame(PhysFrame::containing_address(p1[i1].addr()));
+                                }
+                            }
+                            frame_allocator.deallocate_frame(p1_frame);
+                            table_frames += 1;
+                        }
+                        frame_allocator.deallocate_frame(p2_frame);
+                        table_frames += 1;
+                    }
+                    frame_allocator.deallocate_frame(p3_frame);
+                    table_frames += 1;
+                    p4[i4 as usize].set_unused();
+                }
+            }
+            stats::PAGE_TABLE_FRAMES.fetch_sub(table_frames, Ordering::Relaxed);
+        });
+
+        memory.frame_allocator.deallocate_frame(self.p4_frame);
+        stats::PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,283 @@
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB, Size2MiB};
+use memory::frame_allocator::BitmapFrameAllocator;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// Largest block order handed out: 2^9 frames = 2 MiB, the size of a huge page.
+pub const MAX_ORDER: usize = 9;
+/// Number of frames the buddy allocator can manage: 8 MiB.
+pub const MAX_POOL_FRAMES: usize = 4 << MAX_ORDER;
+const POOL_WORDS: usize = MAX_POOL_FRAMES / BITS_PER_WORD;
+
+/// A buddy system allocator for physically contiguous, naturally aligned blocks
+/// of 2^order frames.
+///
+/// It manages a single contiguous pool of frames. Larger blocks are split in half
+/// (into two "buddies") until a block of the requested order exists, and freed
+/// blocks are merged with their buddy again whenever the buddy is free as well.
+pub struct BuddyAllocator {
+    // first frame of the pool, aligned to a block of MAX_ORDER
+    base: PhysFrame,
+    frame_count: usize,
+    free_frames: usize,
+    // one bitmap per order: bit i set means the block starting at frame
+    // base + (i << order) is free
+    free_blocks: [[u64; POOL_WORDS]; MAX_ORDER + 1],
+}
+
+impl BuddyAllocator {
+    /// Creates a buddy allocator owning the `frame_count` frames starting at `base`.
+    ///
+    /// `base` must be aligned to a block of `MAX_ORDER` so that every block is
+    /// physically aligned to its size.
+    pub fn new(base: PhysFrame, frame_count: usize) -> Self {
+        let max_block_size = FRAME_SIZE << MAX_ORDER;
+        assert!(base.start_address().is_aligned(max_block_size), "buddy pool base is not aligned");
+        assert!(frame_count <= MAX_POOL_FRAMES, "buddy pool is too large");
+
+        let mut allocator = BuddyAllocator {
+            base,
+            frame_count,
+            free_frames: frame_count,
+            free_blocks: [[0; POOL_WORDS]; MAX_ORDER + 1],
+        };
+
+        // split the pool into the largest blocks that fit and are aligned
+        let mut offset = 0;
+        while offset < frame_count {
+            let mut order = MAX_ORDER;
+            while offset % (1 << order) != 0 || offset + (1 << order) > frame_count {
+                order -= 1;
+            }
+            allocator.set_free(order, offset >> order);
+            offset += 1 << order;
+        }
+
+        allocator
+    }
+
+    /// Number of frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Number of frames available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the smallest order whose blocks hold at least `frame_count` frames.
+    pub fn order_for(frame_count: usize) -> usize {
+        frame_count.next_power_of_two().trailing_zeros() as usize
+    }
+
+    /// Allocates a block of 2^order frames and returns its first frame.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // find the smallest free block that is large enough
+        let mut block_order = order;
+        let mut index = loop {
+            if let Some(index) = self.find_free(block_order) {
+                break index;
+            }
+            if block_order == MAX_ORDER {
+                return None;
+            }
+            block_order += 1;
+        };
+        self.clear_free(block_order, index);
+
+        // split the block, keeping the lower half and freeing the upper buddy
+        while block_order > order {
+            block_order -= 1;
+            index *= 2;
+            self.set_free(block_order, index + 1);
+        }
+
+        self.free_frames -= 1 << order;
+        Some(self.base + (index << order) as u64)
+    }
+
+    /// Frees a block of 2^order frames previously returned by `allocate`.
+    pub fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let offset = self.frame_offset(frame)
+            .expect("deallocated frame is not part of the buddy pool");
+        assert!(order <= MAX_ORDER && offset % (1 << order) == 0, "invalid buddy block");
+
+        let mut block_order = order;
+        let mut index = offset >> order;
+        // a freed block may have been merged into a larger one since
+        assert!(!self.is_inside_free_block(order, index), "double free of buddy block {:?}", frame);
+
+        // merge with the buddy for as long as it is free
+        while block_order < MAX_ORDER && self.is_free(block_order, index ^ 1) {
+            self.clear_free(block_order, index ^ 1);
+            index /= 2;
+            block_order += 1;
+        }
+        self.set_free(block_order, index);
+
+        self.free_frames += 1 << order;
+    }
+
+    // Offset of the frame from the pool base, in frames
+    fn frame_offset(&self, frame: PhysFrame) -> Option<usize> {
+        if frame < self.base {
+            return None;
+        }
+        let offset = (frame - self.base) as usize;
+        if offset < self.frame_count {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    // Returns whether the block is free itself or part of a larger free block
+    fn is_inside_free_block(&self, order: usize, index: usize) -> bool {
+        (order..MAX_ORDER + 1)
+            .any(|block_order| self.is_free(block_order, index >> (block_order - order)))
+    }
+
+    fn is_free(&self, order: usize, index: usize) -> bool {
+        index < POOL_WORDS * BITS_PER_WORD
+            && self.free_blocks[order][index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_free(&mut self, order: usize, index: usize) {
+        self.free_blocks[order][index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_free(&mut self, order: usize, index: usize) {
+        self.free_blocks[order][index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    fn find_free(&self, order: usize) -> Option<usize> {
+        self.free_blocks[order].iter()
+            .position(|&word| word != 0)
+            .map(|word| word * BITS_PER_WORD + self.free_blocks[order][word].trailing_zeros() as usize)
+    }
+}
+
+impl FrameAllocator<Size4KiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame, 0)
+    }
+}
+
+// A 2MiB frame is exactly one block of the largest order
+impl FrameAllocator<Size2MiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(MAX_ORDER)
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate(PhysFrame::containing_address(frame.start_address()), MAX_ORDER)
+    }
+}
+
+/// Creates a BuddyAllocator whose pool of `MAX_POOL_FRAMES` frames is taken
+/// from the passed frame allocator.
+///
+/// Returns `None` if no suitably aligned contiguous run of frames is free.
+pub fn init_buddy_allocator(frame_allocator: &mut BitmapFrameAllocator) -> Option<BuddyAllocator> {
+    let base = frame_allocator.allocate_contiguous(MAX_POOL_FRAMES, 1 << MAX_ORDER)?;
+    Some(BuddyAllocator::new(base, MAX_POOL_FRAMES))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x86_64::PhysAddr;
+
+    // Pool starting at 2 MiB, the first aligned block address above frame zero
+    fn pool_base() -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(FRAME_SIZE << MAX_ORDER))
+    }
+
+    #[test]
+    fn splits_and_merges_blocks() {
+        let mut allocator = BuddyAllocator::new(pool_base(), MAX_POOL_FRAMES);
+
+        let single = allocator.allocate(0).unwrap();
+        let pair = allocator.allocate(1).unwrap();
+        assert_eq!(single, pool_base());
+        // the first split left the buddy of order 0 free, so order 1 comes next
+        assert_eq!(pair, pool_base() + 2);
+        assert_eq!(allocator.free_frames(), MAX_POOL_FRAMES - 3);
+
+        allocator.deallocate(single, 0);
+        allocator.deallocate(pair, 1);
+        assert_eq!(allocator.free_frames(), MAX_POOL_FRAMES);
+
+        // everything merged back, so all largest blocks are available again
+        for _ in 0..MAX_POOL_FRAMES >> MAX_ORDER {
+            assert!(allocator.allocate(MAX_ORDER).is_some());
+        }
+        assert_eq!(allocator.allocate(0), None);
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_their_size() {
+        let mut allocator = BuddyAllocator::new(pool_base(), MAX_POOL_FRAMES);
+        allocator.allocate(0).unwrap();
+        for order in 1..MAX_ORDER + 1 {
+            let frame = allocator.allocate(order).unwrap();
+            assert!(frame.start_address().is_aligned(FRAME_SIZE << order));
+        }
+    }
+
+    #[test]
+    fn uneven_pool_size() {
+        // 512 + 256 + 1 frames, which don't form a single block
+        let frame_count = (1 << MAX_ORDER) + (1 << (MAX_ORDER - 1)) + 1;
+        let mut allocator = BuddyAllocator::new(pool_base(), frame_count);
+        assert!(allocator.allocate(MAX_ORDER).is_some());
+        assert!(allocator.allocate(MAX_ORDER).is_none());
+        assert!(allocator.allocate(MAX_ORDER - 1).is_some());
+        assert!(allocator.allocate(0).is_some());
+        assert_eq!(allocator.free_frames(), 0);
+        assert_eq!(allocator.allocate(0), None);
+    }
+
+    #[test]
+    fn serves_huge_frames() {
+        let mut allocator = BuddyAllocator::new(pool_base(), MAX_POOL_FRAMES);
+        let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address(), pool_base().start_address());
+        allocator.deallocate_frame(frame);
+        assert_eq!(allocator.free_frames(), MAX_POOL_FRAMES);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free_of_merged_block_panics() {
+        let mut allocator = BuddyAllocator::new(pool_base(), MAX_POOL_FRAMES);
+        let first = allocator.allocate(0).unwrap();
+        let second = allocator.allocate(0).unwrap();
+        allocator.deallocate(first, 0);
+        // merges the first block into a free block of order 1
+        allocator.deallocate(second, 0);
+        allocator.deallocate(first, 0);
+    }
+
+    #[test]
+    fn order_for_frame_counts() {
+        assert_eq!(BuddyAllocator::order_for(1), 0);
+        assert_eq!(BuddyAllocator::order_for(3), 2);
+        assert_eq!(BuddyAllocator::order_for(512), 9);
+    }
+}
@@ -0,0 +1,93 @@
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags};
+use memory::{out_of_memory, KernelMemory, MemoryError};
+use memory::page_fault::PageWalk;
+use memory::shared_memory::SHARED_MEMORY;
+use memory::stats::PageTableAllocator;
+
+/// Marks a page that shares its frame with other mappings and must be copied before
+/// it is written to. Uses the first bit the CPU leaves to the OS.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Returns the flags of a page once its frame is shared: writable pages become read-only
+/// copy-on-write pages. Read-only pages and pages of shared memory objects stay as they
+/// are.
+pub fn shared_flags(flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED_MEMORY) {
+        flags - PageTableFlags::WRITABLE | COPY_ON_WRITE
+    } else {
+        flags
+    }
+}
+
+/// Returns the flags of a copy-on-write page once it has a frame of its own.
+pub fn private_flags(flags: PageTableFlags) -> PageTableFlags {
+    flags - COPY_ON_WRITE | PageTableFlags::WRITABLE
+}
+
+/// Resolves a write fault on a copy-on-write page of the active address space.
+///
+/// The page gets a copy of the shared frame, or keeps the frame if no other mapping
+/// references it anymore. Returns `false` if the page isn't a copy-on-write page.
+pub fn handle_write_fault(addr: VirtAddr, memory: &mut KernelMemory) -> bool {
+    // unsafe: the recursive entry is set up by the bootloader, and the tables don't
+    // change while the kernel memory is locked
+    let walk = unsafe { PageWalk::new(addr) };
+    let last = walk.steps().last().expect("walk without steps").clone();
+    let flags = last.entry.flags();
+    // copy-on-write pages are always 4KiB pages
+    if !walk.is_mapped() || last.level != 1 || !flags.contains(COPY_ON_WRITE) {
+        return false;
+    }
+    let page = Page::containing_address(addr);
+    let frame = match last.entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+
+    // the last owner takes the frame over
+    if memory.frame_allocator.reference_count(frame) <= 1 {
+        memory.page_table.update_flags(page, private_flags(flags))
+            .expect("faulting page is not mapped")
+            .flush();
+        return true;
+    }
+
+    let copy = match memory.frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => out_of_memory("copy-on-write", MemoryError::FrameAllocationFailed),
+    };
+    memory.with_scratch_page(copy, |bytes| {
+        // unsafe: the faulting page is mapped and readable
+        let source = unsafe { core::slice::from_raw_parts(page.start_address().as_ptr::<u8>(), bytes.len()) };
+        bytes.copy_from_slice(source);
+    });
+
+    // drops this page's reference to the shared frame
+    let (shared, flush) = memory.page_table.unmap(page).expect("faulting page is not mapped");
+    flush.flush();
+    memory.frame_allocator.deallocate_frame(shared);
+    // unsafe: the copy is only mapped here. The parent tables exist, so map_to can't fail
+    unsafe {
+        memory.page_table
+            .map_to(page, copy, private_flags(flags), &mut PageTableAllocator(&mut memory.frame_allocator))
+            .expect("remapping the copied page failed")
+            .flush();
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_writable_pages_become_copy_on_write() {
+        let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let writable = user | PageTableFlags::WRITABLE;
+        assert_eq!(shared_flags(writable), user | COPY_ON_WRITE);
+        assert_eq!(shared_flags(user), user);
+        assert_eq!(private_flags(shared_flags(writable)), writable);
+        assert_eq!(shared_flags(writable | SHARED_MEMORY), writable | SHARED_MEMORY);
+    }
+}
@@ -0,0 +1,436 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::PhysAddr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use memory::MemoryError;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// Amount of physical memory the kernel's frame bitmap can describe.
+/// Usable memory above this limit is ignored.
+pub const MAX_PHYSICAL_MEMORY: u64 = 4 * 1024 * 1024 * 1024;      // 4 GiB
+const MAX_FRAMES: usize = (MAX_PHYSICAL_MEMORY / FRAME_SIZE) as usize;
+/// Number of owners a shared frame can have at most.
+pub const MAX_FRAME_OWNERS: usize = 1 << 16;
+
+// Backing storage for the kernel's bitmap: one bit per 4KiB frame (128 KiB for 4 GiB).
+// Lives in .bss since there is no heap yet when the frame allocator is created
+static mut FRAME_BITMAP: [u64; MAX_FRAMES / BITS_PER_WORD] = [0; MAX_FRAMES / BITS_PER_WORD];
+// The frames of usable regions, the only ones the allocator hands out (128 KiB for 4 GiB)
+static mut FRAME_USABLE: [u64; MAX_FRAMES / BITS_PER_WORD] = [0; MAX_FRAMES / BITS_PER_WORD];
+// Additional references to each frame, e.g. by copy-on-write mappings (2 MiB for 4 GiB)
+static mut FRAME_SHARES: [u16; MAX_FRAMES] = [0; MAX_FRAMES];
+// Guards FRAME_BITMAP, FRAME_USABLE and FRAME_SHARES from being handed out twice
+static FRAME_BITMAP_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// A physical frame allocator that tracks every frame with a single bit.
+///
+/// A set bit means the frame is in use or was never usable, a cleared bit means
+/// the frame is free. Frames can be returned through `FrameDeallocator` and are
+/// then handed out again. Frames outside the usable regions, e.g. of devices, are
+/// ignored when they are returned.
+///
+/// Allocated frames can be shared, e.g. between address spaces. A shared frame is only
+/// freed once it has been deallocated by every owner.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // a set bit for every frame of a usable region, tells allocated frames apart from
+    // the ones never usable
+    usable: &'static mut [u64],
+    // number of owners beyond the first for every allocated frame
+    shares: &'static mut [u16],
+    // number of frames that were marked usable by the bootloader
+    total_frames: usize,
+    free_frames: usize,
+    // word to start the next search at, avoids rescanning the fully used start of the bitmap
+    next_word: usize,
+    // one past the last word containing a usable frame, no need to search beyond
+    word_limit: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Creates an allocator managing the `Usable` regions of the memory map, using
+    /// `bitmap` to store the frame states, `usable` to record the usable frames and
+    /// `shares` to count additional owners.
+    ///
+    /// Frames not covered by the bitmap are ignored. `usable` must have the size of the
+    /// bitmap, `shares` needs an entry for every frame of the bitmap.
+    pub fn new(memory_map: &MemoryMap, bitmap: &'static mut [u64], usable: &'static mut [u64],
+               shares: &'static mut [u16]) -> Self {
+        assert_eq!(usable.len(), bitmap.len(), "usable frames don't match the bitmap");
+        assert!(shares.len() >= bitmap.len() * BITS_PER_WORD, "share counts don't cover the bitmap");
+        // start with every frame unavailable and only release the usable ones
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        for word in usable.iter_mut() {
+            *word = 0;
+        }
+        for count in shares.iter_mut() {
+            *count = 0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            usable,
+            shares,
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+            word_limit: 0,
+        };
+
+        let frame_count = allocator.bitmap.len() * BITS_PER_WORD;
+        // the bootloader page aligns all usable regions, so frame numbers are exact
+        let usable_regions = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        for region in usable_regions {
+            let start = region.range.start_frame_number as usize;
+            let end = (region.range.end_frame_number as usize).min(frame_count);
+            for index in start..end {
+                allocator.clear_bit(index);
+                allocator.usable[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+                allocator.total_frames += 1;
+            }
+            if end > start {
+                let end_word = (end + BITS_PER_WORD - 1) / BITS_PER_WORD;
+                allocator.word_limit = allocator.word_limit.max(end_word);
+            }
+        }
+        allocator.free_frames = allocator.total_frames;
+
+        allocator
+    }
+
+    /// Number of usable frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Number of frames available for allocation.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames currently handed out.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Returns whether the given frame is currently free.
+    pub fn is_free(&self, frame: PhysFrame) -> bool {
+        match self.managed_index(frame) {
+            Some(index) => !self.bit(index),
+            None => false,
+        }
+    }
+
+    /// Returns whether the frame is part of a usable region, and so handed out by this
+    /// allocator.
+    pub fn is_managed(&self, frame: PhysFrame) -> bool {
+        self.managed_index(frame).is_some()
+    }
+
+    /// Adds an owner to an allocated frame, which then needs one more deallocation
+    /// before it is free again. Frames not managed by this allocator are never freed,
+    /// so they are left alone.
+    ///
+    /// Fails with `TooManyOwners` if the frame has `MAX_FRAME_OWNERS` already.
+    pub fn share_frame(&mut self, frame: PhysFrame) -> Result<(), MemoryError> {
+        if !self.is_managed(frame) {
+            return Ok(());
+        }
+        let index = self.allocated_index(frame);
+        self.shares[index] = self.shares[index].checked_add(1).ok_or(MemoryError::TooManyOwners)?;
+        Ok(())
+    }
+
+    /// Returns the number of owners of the frame, 0 if it is free or not managed by
+    /// this allocator.
+    pub fn reference_count(&self, frame: PhysFrame) -> usize {
+        match self.managed_index(frame) {
+            Some(index) if self.bit(index) => 1 + self.shares[index] as usize,
+            _ => 0,
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames, with the first frame aligned
+    /// to `align` frames. Returns the first frame of the run.
+    ///
+    /// The frames are individually freed through `FrameDeallocator`.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(count > 0 && align.is_power_of_two());
+        if count > self.free_frames {
+            return None;
+        }
+
+        let limit = self.word_limit * BITS_PER_WORD;
+        let mut start = 0;
+        while start + count <= limit {
+            // on a used frame restart the search at the next aligned index behind it
+            match (start..start + count).find(|&index| self.bit(index)) {
+                Some(used) => start = (used + align) & !(align - 1),
+                None => {
+                    for index in start..start + count {
+                        self.set_bit(index);
+                    }
+                    self.free_frames -= count;
+                    let addr = PhysAddr::new(start as u64 * FRAME_SIZE);
+                    return Some(PhysFrame::containing_address(addr));
+                }
+            }
+        }
+        None
+    }
+
+    fn frame_index(frame: PhysFrame) -> Option<usize> {
+        let index = frame.start_address().as_u64() / FRAME_SIZE;
+        if index < MAX_FRAMES as u64 {
+            Some(index as usize)
+        } else {
+            None
+        }
+    }
+
+    // Returns the index of a frame of a usable region covered by the bitmap
+    fn managed_index(&self, frame: PhysFrame) -> Option<usize> {
+        Self::frame_index(frame)
+            .filter(|&index| index < self.bitmap.len() * BITS_PER_WORD)
+            .filter(|&index| self.usable[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0)
+    }
+
+    // Returns the index of a frame that must be managed by this allocator and allocated
+    fn allocated_index(&self, frame: PhysFrame) -> usize {
+        let index = self.managed_index(frame)
+            .expect("frame is not managed by the frame allocator");
+        // a cleared bit means the frame is free
+        assert!(self.bit(index), "frame {:?} is not allocated", frame);
+        index
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    // Finds the first word with a free frame, starting at the hint and wrapping around once
+    fn find_free_word(&self) -> Option<usize> {
+        (self.next_word..self.word_limit)
+            .chain(0..self.next_word)
+            .find(|&word| self.bitmap[word] != !0)
+    }
+}
+
+impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        let word = self.find_free_word()?;
+        // the lowest cleared bit is the first free frame of the word
+        let bit = (!self.bitmap[word]).trailing_zeros() as usize;
+        let index = word * BITS_PER_WORD + bit;
+
+        self.set_bit(index);
+        self.free_frames -= 1;
+        self.next_word = word;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        // frames of devices or reserved memory, e.g. of an unmapped VGA buffer mapping,
+        // were never handed out and must not become free memory
+        if !self.is_managed(frame) {
+            return;
+        }
+        // panics on a double free, since the frame isn't allocated anymore
+        let index = self.allocated_index(frame);
+        // a shared frame stays allocated for the remaining owners
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+            return;
+        }
+
+        self.clear_bit(index);
+        self.free_frames += 1;
+        // freed frames below the hint would otherwise only be found after wrapping around
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+    }
+}
+
+/// Creates the kernel's BitmapFrameAllocator from the passed memory map.
+///
+/// Panics if called more than once, since the bitmap storage can only be owned
+/// by a single allocator.
+pub fn init_bitmap_frame_allocator(memory_map: &'static MemoryMap) -> BitmapFrameAllocator {
+    let already_taken = FRAME_BITMAP_TAKEN.swap(true, Ordering::SeqCst);
+    assert!(!already_taken, "bitmap frame allocator already initialized");
+
+    // unsafe: FRAME_BITMAP_TAKEN ensures these are the only references to the statics
+    let (bitmap, usable, shares) = unsafe {
+        (&mut FRAME_BITMAP[..], &mut FRAME_USABLE[..], &mut FRAME_SHARES[..])
+    };
+    BitmapFrameAllocator::new(memory_map, bitmap, usable, shares)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+    use bootloader::bootinfo::{MemoryRegion, FrameRange};
+
+    fn region(start_frame: u64, end_frame: u64, region_type: MemoryRegionType) -> MemoryRegion {
+        MemoryRegion {
+            range: FrameRange { start_frame_number: start_frame, end_frame_number: end_frame },
+            region_type,
+        }
+    }
+
+    // Two usable regions separated by an in use one, followed by reserved memory
+    fn construct_allocator() -> BitmapFrameAllocator {
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(region(0, 1, MemoryRegionType::FrameZero));
+        memory_map.add_region(region(1, 100, MemoryRegionType::Usable));
+        memory_map.add_region(region(100, 150, MemoryRegionType::Kernel));
+        memory_map.add_region(region(150, 300, MemoryRegionType::Usable));
+        memory_map.add_region(region(300, 400, MemoryRegionType::Reserved));
+
+        let bitmap = &mut Box::leak(Box::new([0u64; 16]))[..];
+        let usable = &mut Box::leak(Box::new([0u64; 16]))[..];
+        let shares = &mut Box::leak(Box::new([0u16; 16 * BITS_PER_WORD]))[..];
+        BitmapFrameAllocator::new(&memory_map, bitmap, usable, shares)
+    }
+
+    fn frame_number(frame: PhysFrame) -> u64 {
+        frame.start_address().as_u64() / FRAME_SIZE
+    }
+
+    #[test]
+    fn counts_usable_frames() {
+        let allocator = construct_allocator();
+        assert_eq!(allocator.total_frames(), 99 + 150);
+        assert_eq!(allocator.free_frames(), allocator.total_frames());
+        assert_eq!(allocator.used_frames(), 0);
+    }
+
+    #[test]
+    fn allocates_only_usable_frames() {
+        let mut allocator = construct_allocator();
+        let mut frames = Vec::new();
+        while let Some(frame) = allocator.allocate_frame() {
+            frames.push(frame_number(frame));
+        }
+
+        assert_eq!(frames.len(), allocator.total_frames());
+        assert_eq!(allocator.free_frames(), 0);
+        for &number in frames.iter() {
+            assert!((1 <= number && number < 100) || (150 <= number && number < 300));
+        }
+        // every frame is only handed out once
+        frames.sort();
+        frames.dedup();
+        assert_eq!(frames.len(), allocator.total_frames());
+    }
+
+    #[test]
+    fn reuses_deallocated_frames() {
+        let mut allocator = construct_allocator();
+        while allocator.allocate_frame().is_some() {}
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(170 * FRAME_SIZE));
+        assert!(!allocator.is_free(frame));
+        allocator.deallocate_frame(frame);
+        assert!(allocator.is_free(frame));
+        assert_eq!(allocator.free_frames(), 1);
+
+        assert_eq!(allocator.allocate_frame(), Some(frame));
+        assert_eq!(allocator.allocate_frame(), None);
+        assert_eq!(allocator.used_frames(), allocator.total_frames());
+    }
+
+    #[test]
+    fn allocates_aligned_contiguous_frames() {
+        let mut allocator = construct_allocator();
+        // the first usable region is too short for an aligned run of 64 frames
+        let first = allocator.allocate_contiguous(64, 64).unwrap();
+        assert_eq!(frame_number(first), 192);
+        assert_eq!(allocator.free_frames(), allocator.total_frames() - 64);
+        for number in 192..256 {
+            let frame = PhysFrame::containing_address(PhysAddr::new(number * FRAME_SIZE));
+            assert!(!allocator.is_free(frame));
+        }
+
+        // only 44 frames remain behind the run in the second region
+        assert_eq!(allocator.allocate_contiguous(64, 64), None);
+        let second = allocator.allocate_contiguous(40, 4).unwrap();
+        assert_eq!(frame_number(second), 4);
+    }
+
+    #[test]
+    fn shared_frames_are_freed_by_the_last_owner() {
+        let mut allocator = construct_allocator();
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.reference_count(frame), 1);
+        allocator.share_frame(frame).unwrap();
+        allocator.share_frame(frame).unwrap();
+        assert_eq!(allocator.reference_count(frame), 3);
+
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_frame(frame);
+        assert!(!allocator.is_free(frame));
+        assert_eq!(allocator.reference_count(frame), 1);
+        allocator.deallocate_frame(frame);
+        assert!(allocator.is_free(frame));
+        assert_eq!(allocator.reference_count(frame), 0);
+        assert_eq!(allocator.used_frames(), 0);
+    }
+
+    #[test]
+    fn limits_the_owners_of_a_frame() {
+        let mut allocator = construct_allocator();
+        let frame = allocator.allocate_frame().unwrap();
+        for _ in 1..MAX_FRAME_OWNERS {
+            allocator.share_frame(frame).unwrap();
+        }
+        assert_eq!(allocator.reference_count(frame), MAX_FRAME_OWNERS);
+        assert_eq!(allocator.share_frame(frame), Err(MemoryError::TooManyOwners));
+        assert_eq!(allocator.reference_count(frame), MAX_FRAME_OWNERS);
+    }
+
+    #[test]
+    fn ignores_frames_outside_usable_regions() {
+        let mut allocator = construct_allocator();
+        for &number in [0, 120, 350, 2000].iter() {
+            let frame = PhysFrame::containing_address(PhysAddr::new(number * FRAME_SIZE));
+            assert!(!allocator.is_managed(frame));
+            assert_eq!(allocator.share_frame(frame), Ok(()));
+            allocator.deallocate_frame(frame);
+            assert!(!allocator.is_free(frame));
+            assert_eq!(allocator.reference_count(frame), 0);
+        }
+        assert_eq!(allocator.free_frames(), allocator.total_frames());
+        assert_eq!(allocator.used_frames(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free_panics() {
+        let mut allocator = construct_allocator();
+        let frame = allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_frame(frame);
+    }
+}
@@ -0,0 +1,260 @@
+use core::fmt::{self, Write};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use memory::page_fault::{PageWalk, RECURSIVE_INDEX, table_address};
+
+// Size of the 48 bit virtual address space, covering both canonical halves
+const ADDRESS_SPACE_SIZE: u64 = 1 << 48;
+
+/// Size of a page, or of the range of addresses skipped at an unused entry, mapped
+/// by an entry at the given level.
+pub fn level_size(level: u8) -> u64 {
+    4096 << (9 * (u64::from(level) - 1))
+}
+
+/// A range of virtual memory mapped to contiguous physical memory with the same
+/// flags and page size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub phys_start: PhysAddr,
+    pub size: u64,
+    pub page_size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    // Extends this range by the page if it continues it seamlessly
+    fn merge(&mut self, page: &MappedRange) -> bool {
+        let continues = self.end() == page.start
+            && self.phys_start + self.size == page.phys_start
+            && self.page_size == page.page_size
+            && self.flags == page.flags;
+        if continues {
+            self.size += page.size;
+        }
+        continues
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}-{:#018x} -> {:#012x} {:>9} in {:>4} pages  {:?}",
+               self.start.as_u64(), self.end().as_u64(), self.phys_start.as_u64(),
+               Size(self.size), Size(self.page_size), self.flags)
+    }
+}
+
+/// Formats a byte count with the largest binary unit that divides it.
+pub struct Size(pub u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // formatted into a buffer first so that width and alignment can be applied
+        let mut buffer = FormatBuffer { bytes: [0; 32], len: 0 };
+        let units = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10), ("B", 1)];
+        for &(unit, unit_size) in units.iter() {
+            if (self.0 >= unit_size && self.0 % unit_size == 0) || unit_size == 1 {
+                write!(buffer, "{}{}", self.0 / unit_size, unit)?;
+                break;
+            }
+        }
+        f.pad(buffer.as_str())
+    }
+}
+
+// Fixed size buffer to format short strings without the heap
+struct FormatBuffer {
+    bytes: [u8; 32],
+    len: usize,
+}
+
+impl FormatBuffer {
+    fn as_str(&self) -> &str {
+        // only whole strs are ever written
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+impl fmt::Write for FormatBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.bytes.len() {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Iterates over the mapped memory of an address space, merging consecutive pages
+/// into `MappedRange`s in ascending address order.
+///
+/// The recursive P4 entry is skipped, since it maps the page tables themselves.
+pub struct Mappings<F> {
+    // returns the table of the given level used to translate the address
+    table: F,
+    // next address to visit, as offset into the 48 bit address space
+    next: u64,
+    pending: Option<MappedRange>,
+}
+
+/// Returns the mappings of the active page table.
+///
+/// Unsafe: the recursive entry must be set up in the active P4 table, and the
+/// tables must not change while iterating.
+pub unsafe fn active_mappings() -> Mappings<fn(u8, VirtAddr) -> &'static PageTable> {
+    fn recursive_table(level: u8, addr: VirtAddr) -> &'static PageTable {
+        // safety is guaranteed by the caller of active_mappings
+        unsafe { &*table_address(addr, level).as_ptr::<PageTable>() }
+    }
+    Mappings::new(recursive_table)
+}
+
+impl<'a, F> Mappings<F> where F: Fn(u8, VirtAddr) -> &'a PageTable {
+    pub fn new(table: F) -> Self {
+        Mappings { table, next: 0, pending: None }
+    }
+
+    // Returns the next mapped page, huge pages count as a single page
+    fn next_page(&mut self) -> Option<MappedRange> {
+        while self.next < ADDRESS_SPACE_SIZE {
+            // new_unchecked sign extends the offset into a canonical address
+            let addr = VirtAddr::new_unchecked(self.next);
+            if u64::from(addr.p4_index()) == RECURSIVE_INDEX {
+                self.next += level_size(4);
+                continue;
+            }
+
+            let table = &self.table;
+            let walk = PageWalk::walk_with(addr, |level| table(level, addr));
+            let last = walk.steps().last().expect("walk without steps");
+            let size = level_size(last.level);
+            // the walk may start in the middle of a larger unused range
+            self.next = (self.next & !(size - 1)) + size;
+
+            if walk.is_mapped() {
+                return Some(MappedRange {
+                    start: addr,
+                    phys_start: last.entry.addr(),
+                    size,
+                    page_size: size,
+                    // set by the CPU on access, they would prevent merging otherwise equal pages
+                    flags: last.entry.flags() - PageTableFlags::ACCESSED - PageTableFlags::DIRTY,
+                });
+            }
+        }
+        None
+    }
+}
+
+impl<'a, F> Iterator for Mappings<F> where F: Fn(u8, VirtAddr) -> &'a PageTable {
+    type Item = MappedRange;
+
+    fn next(&mut self) -> Option<MappedRange> {
+        let mut range = match self.pending.take() {
+            Some(range) => range,
+            None => self.next_page()?,
+        };
+        while let Some(page) = self.next_page() {
+            if !range.merge(&page) {
+                self.pending = Some(page);
+                break;
+            }
+        }
+        Some(range)
+    }
+}
+
+/// Prints all mapped ranges of the active address space to serial, one per line,
+/// followed by the total amount of mapped memory.
+pub fn dump_mappings() {
+    let mut ranges = 0;
+    let mut total = 0;
+    serial_println!("Mappings:");
+    // unsafe: the bootloader sets up the recursive entry. Pages mapped while the
+    // dump runs may or may not be shown
+    for range in unsafe { active_mappings() } {
+        serial_println!("  {}", range);
+        ranges += 1;
+        total += range.size;
+    }
+    serial_println!("  {} ranges, {} mapped", ranges, Size(total));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+    use std::string::ToString;
+
+    // A P4 with one P3 at index 0 mapping one huge 1GiB page at 1GiB, and one P2 at
+    // index 0 with two huge 2MiB pages and one P1 with three 4KiB pages
+    struct Tables {
+        p4: PageTable,
+        p3: PageTable,
+        p2: PageTable,
+        p1: PageTable,
+    }
+
+    fn tables() -> Box<Tables> {
+        let mut tables = Box::new(Tables {
+            p4: PageTable::new(), p3: PageTable::new(), p2: PageTable::new(), p1: PageTable::new(),
+        });
+        let table = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let huge = table | PageTableFlags::HUGE_PAGE;
+        tables.p4[0].set_addr(PhysAddr::new(0x1000), table);
+        tables.p3[0].set_addr(PhysAddr::new(0x2000), table);
+        tables.p3[1].set_addr(PhysAddr::new(0x4000_0000), huge);
+        tables.p2[0].set_addr(PhysAddr::new(0x3000), table);
+        tables.p2[1].set_addr(PhysAddr::new(0x20_0000), huge);
+        tables.p2[2].set_addr(PhysAddr::new(0x40_0000), huge | PageTableFlags::ACCESSED);
+        tables.p1[1].set_addr(PhysAddr::new(0xb8000), table);
+        tables.p1[2].set_addr(PhysAddr::new(0xb9000), table);
+        tables.p1[3].set_addr(PhysAddr::new(0xb0000), table | PageTableFlags::NO_EXECUTE);
+        // the recursive entry must not show up
+        tables.p4[511].set_addr(PhysAddr::new(0x5000), table);
+        tables
+    }
+
+    #[test]
+    fn merges_contiguous_pages() {
+        let tables = tables();
+        let ranges: Vec<MappedRange> = Mappings::new(|level, _addr: VirtAddr| {
+            match level {
+                4 => &tables.p4,
+                3 => &tables.p3,
+                2 => &tables.p2,
+                _ => &tables.p1,
+            }
+        }).collect();
+
+        let sizes: Vec<(u64, u64, u64)> = ranges.iter()
+            .map(|range| (range.start.as_u64(), range.size, range.page_size))
+            .collect();
+        let expected: [(u64, u64, u64); 4] = [
+            (0x1000, 0x2000, 0x1000),
+            (0x3000, 0x1000, 0x1000),
+            (0x20_0000, 0x40_0000, 0x20_0000),
+            (0x4000_0000, 0x4000_0000, 0x4000_0000),
+        ];
+        assert_eq!(&sizes[..], &expected[..]);
+        assert_eq!(ranges[2].flags, PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+            | PageTableFlags::HUGE_PAGE);
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(Size(4096).to_string(), "4KiB");
+        assert_eq!(Size(3 << 20).to_string(), "3MiB");
+        assert_eq!(Size(1 << 30).to_string(), "1GiB");
+        assert_eq!(Size(1000).to_string(), "1000B");
+        assert_eq!(std::format!("{:>6}|", Size(8192)), "  8KiB|");
+    }
+}
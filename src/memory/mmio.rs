@@ -0,0 +1,268 @@
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::tlb;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{Mapper, Page, PageRange, PageSize, PageTableFlags,
+                                 PhysFrame, Size4KiB};
+use memory::{KernelMemory, MemoryError, KERNEL_MEMORY};
+use memory::stats::PageTableAllocator;
+use memory::vma::{self, Backing};
+
+/// Start of the virtual range device memory is mapped into (P4 index 192).
+pub const MMIO_START: u64 = 0x_6000_0000_0000;
+/// Size of the device memory range, a whole P4 entry.
+pub const MMIO_SIZE: u64 = 512 << 30;
+
+/// Bit 7 of a P1 entry selects the upper half of the PAT. Only in P2 and P3 entries it
+/// is the huge page bit.
+pub const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+// IA32_PAT model specific register
+const IA32_PAT: u32 = 0x277;
+// Memory types of the PAT entries
+const UNCACHEABLE: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+const WRITE_THROUGH: u64 = 0x04;
+const WRITE_BACK: u64 = 0x06;
+const UNCACHED_MINUS: u64 = 0x07;
+/// PAT entries 0 to 7: the power-on default, except entry 4 which is changed to
+/// write-combining. Entries 0 to 3 are what PWT and PCD select without the PAT bit.
+pub const PAT_VALUE: u64 = WRITE_BACK | WRITE_THROUGH << 8 | UNCACHED_MINUS << 16 | UNCACHEABLE << 24
+    | WRITE_COMBINING << 32 | WRITE_THROUGH << 40 | UNCACHED_MINUS << 48 | UNCACHEABLE << 56;
+
+// Set once PAT entry 4 is write-combining
+static PAT_PROGRAMMED: AtomicBool = AtomicBool::new(false);
+// Next free page of the device memory range. Ranges are never reused, the range is
+// large enough for any number of devices
+static NEXT_PAGE: Mutex<u64> = Mutex::new(MMIO_START);
+
+/// How the CPU caches accesses to a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal memory.
+    WriteBack,
+    /// Reads are cached, writes go to memory immediately.
+    WriteThrough,
+    /// Every access goes to the device, e.g. for registers.
+    Uncached,
+    /// Writes are buffered and combined, e.g. for framebuffers. Falls back to
+    /// `Uncached` if the CPU has no PAT.
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Returns the page table flags selecting the PAT entry for the mode, assuming the
+    /// PAT was programmed by `init_pat`.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PAT_4KIB,
+        }
+    }
+}
+
+/// Programs the PAT so that write-combining can be selected by page table flags.
+///
+/// Returns `false` if the CPU has no PAT. Called by `init`.
+pub fn init_pat() -> bool {
+    // unsafe: CPUID leaf 1 exists on every x86_64 CPU
+    let has_pat = unsafe { core::arch::x86_64::__cpuid(1) }.edx & (1 << 16) != 0;
+    if !has_pat {
+        return false;
+    }
+
+    // unsafe: entries 0 to 3 keep their values, and no mapping sets the PAT bit yet, so
+    // no cached data changes its type and no cache flush is needed
+    unsafe { Msr::new(IA32_PAT).write(PAT_VALUE) };
+    tlb::flush_all();
+    PAT_PROGRAMMED.store(true, Ordering::Release);
+    true
+}
+
+/// Reserves the device memory range and programs the PAT. Called by
+/// `init_kernel_memory`.
+pub fn init() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vma::reserve("device memory", VirtAddr::new(MMIO_START), MMIO_SIZE, flags, Backing::Mapped)
+        .expect("device memory range overlaps a reserved area");
+    init_pat();
+}
+
+/// Maps `size` bytes of device memory at `phys_addr` with the cache mode and returns a
+/// volatile accessor of type `T` for it. The mapping is removed when it is dropped.
+///
+/// `T` is usually a `#[repr(C)]` struct of `Volatile` registers. Mappings are
+/// writable and not executable.
+///
+/// Unsafe: the physical range must be device memory, or memory that isn't used
+/// otherwise, and `T` must match the layout of the device's registers. The range must
+/// not be mapped with a different cache mode anywhere else, e.g. the linear mapping of
+/// physical memory.
+pub unsafe fn ioremap<T>(phys_addr: PhysAddr, size: u64, mode: CacheMode)
+                         -> Result<Mmio<T>, MemoryError> {
+    assert!(size >= mem::size_of::<T>() as u64, "device memory smaller than its type");
+    assert!(phys_addr.is_aligned(mem::align_of::<T>() as u64), "misaligned device memory");
+    let mode = if mode == CacheMode::WriteCombining && !PAT_PROGRAMMED.load(Ordering::Acquire) {
+        CacheMode::Uncached
+    } else {
+        mode
+    };
+
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+    let offset = phys_addr - first_frame.start_address();
+    let count = (offset + size + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    let start = {
+        let mut next_page = NEXT_PAGE.lock();
+        let start = *next_page;
+        // an unmapped guard page separates the mappings
+        let end = start + (count + 1) * Size4KiB::SIZE;
+        if end > MMIO_START + MMIO_SIZE {
+            return Err(MemoryError::AddressRangeExhausted);
+        }
+        *next_page = end;
+        Page::containing_address(VirtAddr::new(start))
+    };
+    let pages = Page::range(start, start + count);
+
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for (i, page) in pages.enumerate() {
+        let frame = first_frame + i as u64;
+        // map_to rejects the PAT bit, since it is the huge page bit in other tables
+        let result = memory.page_table
+            .map_to(page, frame, flags | (mode.flags() - PAT_4KIB),
+                    &mut PageTableAllocator(&mut memory.frame_allocator))
+            .map(|flush| flush.flush());
+        if let Err(err) = result {
+            unmap_pages(memory, Page::range(start, page));
+            return Err(err.into());
+        }
+        if mode.flags().contains(PAT_4KIB) {
+            memory.page_table.update_flags(page, flags | mode.flags())
+                .expect("device page mapped above is missing")
+                .flush();
+        }
+    }
+
+    Ok(Mmio {
+        addr: start.start_address() + offset,
+        phys_addr,
+        size,
+        pages,
+        mode,
+        _type: PhantomData,
+    })
+}
+
+// Removes device memory mappings, the frames aren't returned to any allocator
+fn unmap_pages(memory: &mut KernelMemory, pages: PageRange) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in pages {
+        // unmap can't read the frame of an entry with the PAT bit set
+        memory.page_table.update_flags(page, flags).expect("device page is not mapped").flush();
+        memory.page_table.unmap(page).expect("device page is not mapped").1.flush();
+    }
+}
+
+/// A mapping of device memory created by `ioremap`, accessible as `T` through `Deref`
+/// or by byte offset with `read` and `write`.
+pub struct Mmio<T> {
+    addr: VirtAddr,
+    phys_addr: PhysAddr,
+    size: u64,
+    pages: PageRange,
+    mode: CacheMode,
+    _type: PhantomData<T>,
+}
+
+impl<T> Mmio<T> {
+    /// Virtual address of the physical address passed to `ioremap`.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The cache mode actually used, see `CacheMode::WriteCombining`.
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    /// Reads a value at the byte offset with a single volatile access.
+    pub fn read<U: Copy>(&self, offset: u64) -> U {
+        // unsafe: the bounds and the alignment are checked
+        unsafe { self.ptr::<U>(offset).read_volatile() }
+    }
+
+    /// Writes a value at the byte offset with a single volatile access.
+    pub fn write<U: Copy>(&mut self, offset: u64, value: U) {
+        // unsafe: the bounds and the alignment are checked
+        unsafe { self.ptr::<U>(offset).write_volatile(value) }
+    }
+
+    fn ptr<U>(&self, offset: u64) -> *mut U {
+        assert!(offset + mem::size_of::<U>() as u64 <= self.size, "access beyond device memory");
+        let addr = self.addr + offset;
+        assert!(addr.is_aligned(mem::align_of::<U>() as u64), "misaligned device memory access");
+        addr.as_mut_ptr()
+    }
+}
+
+impl<T> Deref for Mmio<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // unsafe: size and alignment were checked by ioremap
+        unsafe { &*self.addr.as_ptr() }
+    }
+}
+
+impl<T> DerefMut for Mmio<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // unsafe: size and alignment were checked by ioremap
+        unsafe { &mut *self.addr.as_mut_ptr() }
+    }
+}
+
+// Takes the KERNEL_MEMORY lock, so mappings must not be dropped while it is held
+impl<T> Drop for Mmio<T> {
+    fn drop(&mut self) {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        unmap_pages(memory, self.pages);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pat_keeps_default_entries() {
+        // the power-on default, which the flags without the PAT bit still select
+        assert_eq!(PAT_VALUE & 0xffff_ffff, 0x0007_0406);
+        let entry = |flags: PageTableFlags| {
+            let index = (flags.contains(PAT_4KIB) as u64) << 2
+                | (flags.contains(PageTableFlags::NO_CACHE) as u64) << 1
+                | flags.contains(PageTableFlags::WRITE_THROUGH) as u64;
+            (PAT_VALUE >> (index * 8)) & 0xff
+        };
+        assert_eq!(entry(CacheMode::WriteBack.flags()), WRITE_BACK);
+        assert_eq!(entry(CacheMode::WriteThrough.flags()), WRITE_THROUGH);
+        assert_eq!(entry(CacheMode::Uncached.flags()), UNCACHEABLE);
+        assert_eq!(entry(CacheMode::WriteCombining.flags()), WRITE_COMBINING);
+    }
+}
@@ -0,0 +1,256 @@
+use core::fmt;
+use x86_64::VirtAddr;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{PageTable, PageTableEntry, PageTableFlags};
+use memory::vma::{self, VirtualMemoryArea};
+
+/// P4 index of the recursive entry, set up by the bootloader.
+pub const RECURSIVE_INDEX: u64 = 511;
+
+/// Returns the virtual address of the level `level` table (4 = P4, 1 = P1) that is
+/// used to translate `addr`, accessed through the recursive P4 entry.
+pub fn table_address(addr: VirtAddr, level: u8) -> VirtAddr {
+    recursive_table_address(addr, level, RECURSIVE_INDEX)
+}
+
+/// Same as `table_address`, but through the recursive entry at `recursive_index`.
+///
+/// Each recursive index in front of the address skips one level of the translation.
+pub fn recursive_table_address(addr: VirtAddr, level: u8, recursive_index: u64) -> VirtAddr {
+    assert!(level >= 1 && level <= 4, "invalid page table level {}", level);
+    let indices = [
+        u64::from(addr.p4_index()),
+        u64::from(addr.p3_index()),
+        u64::from(addr.p2_index()),
+    ];
+
+    let mut table_addr = 0;
+    for i in 0..4 {
+        let index = if i < level as usize {
+            recursive_index
+        } else {
+            indices[i - level as usize]
+        };
+        table_addr |= index << (39 - 9 * i);
+    }
+    // new_unchecked sign extends bit 47, which is set by the recursive index
+    VirtAddr::new_unchecked(table_addr)
+}
+
+/// One entry visited while translating an address.
+#[derive(Debug, Clone)]
+pub struct WalkStep {
+    pub level: u8,
+    pub index: u16,
+    pub entry: PageTableEntry,
+}
+
+/// The page table entries used to translate an address, from P4 downwards.
+///
+/// The walk stops early at an entry that isn't present or maps a huge page.
+#[derive(Debug, Clone)]
+pub struct PageWalk {
+    steps: [Option<WalkStep>; 4],
+}
+
+impl PageWalk {
+    /// Walks the active page table through the recursive mapping.
+    ///
+    /// Unsafe: the recursive entry must be set up in the active P4 table.
+    pub unsafe fn new(addr: VirtAddr) -> PageWalk {
+        PageWalk::walk_with(addr, |level| &*table_address(addr, level).as_ptr::<PageTable>())
+    }
+
+    // Walks the tables returned by `table`, which only gets called for levels whose
+    // parent entry is present
+    pub(crate) fn walk_with<'a, F>(addr: VirtAddr, table: F) -> PageWalk
+        where F: Fn(u8) -> &'a PageTable
+    {
+        let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+        let mut walk = PageWalk { steps: [None, None, None, None] };
+
+        for (i, &index) in indices.iter().enumerate() {
+            let level = 4 - i as u8;
+            let entry = table(level)[index].clone();
+            let flags = entry.flags();
+            walk.steps[i] = Some(WalkStep { level, index: u16::from(index), entry });
+
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+                break;
+            }
+        }
+        walk
+    }
+
+    /// Iterates over the visited entries, starting with the P4 entry.
+    pub fn steps(&self) -> impl Iterator<Item = &WalkStep> {
+        self.steps.iter().filter_map(|step| step.as_ref())
+    }
+
+    /// Returns whether the walk reached a present page.
+    pub fn is_mapped(&self) -> bool {
+        let last = self.steps().last().expect("walk without steps");
+        let flags = last.entry.flags();
+        flags.contains(PageTableFlags::PRESENT)
+            && (last.level == 1 || flags.contains(PageTableFlags::HUGE_PAGE))
+    }
+}
+
+impl fmt::Display for PageWalk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in self.steps() {
+            write!(f, "  P{}[{:3}] ", step.level, step.index)?;
+            if step.entry.is_unused() {
+                writeln!(f, "unused")?;
+            } else {
+                writeln!(f, "{:#x} {:?}", step.entry.addr().as_u64(), step.entry.flags())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wraps a page fault error code to print its bits as words.
+pub struct ErrorCodeDescription(pub PageFaultErrorCode);
+
+impl fmt::Display for ErrorCodeDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        write!(f, "{:#x}: ", code.bits())?;
+        if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            write!(f, "protection violation")?;
+        } else {
+            write!(f, "page not present")?;
+        }
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            write!(f, ", instruction fetch")?;
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            write!(f, ", write")?;
+        } else {
+            write!(f, ", read")?;
+        }
+        if code.contains(PageFaultErrorCode::USER_MODE) {
+            write!(f, ", user mode")?;
+        } else {
+            write!(f, ", kernel mode")?;
+        }
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in page table entry")?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything known about a page fault, printed as a multi line report.
+pub struct PageFaultReport {
+    pub addr: VirtAddr,
+    pub error_code: PageFaultErrorCode,
+    pub walk: PageWalk,
+    // None if no area contains the address, or the registry was locked
+    pub area: Option<VirtualMemoryArea>,
+}
+
+impl PageFaultReport {
+    /// Collects the report for a fault at `addr` in the active address space.
+    pub fn new(addr: VirtAddr, error_code: PageFaultErrorCode) -> PageFaultReport {
+        // unsafe: the bootloader sets up the recursive entry and it is never removed
+        let walk = unsafe { PageWalk::new(addr) };
+        // try_lock: the fault may have happened while the registry was locked
+        let area = vma::VIRTUAL_MEMORY_AREAS.try_lock().and_then(|areas| areas.find(addr));
+        PageFaultReport { addr, error_code, walk, area }
+    }
+}
+
+impl fmt::Display for PageFaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Accessed Address: {:#x}", self.addr.as_u64())?;
+        writeln!(f, "Error Code: {}", ErrorCodeDescription(self.error_code))?;
+        match self.area {
+            Some(ref area) => writeln!(f, "Region: {} ({:#x}..{:#x})", area.name,
+                                       area.start.as_u64(), area.end.as_u64())?,
+            None => writeln!(f, "Region: unknown")?,
+        }
+        writeln!(f, "Page Table Walk:")?;
+        write!(f, "{}", self.walk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::boxed::Box;
+    use std::string::ToString;
+    use x86_64::PhysAddr;
+
+    fn entry(addr: u64, flags: PageTableFlags) -> PageTableEntry {
+        let mut entry = PageTableEntry::new();
+        entry.set_addr(PhysAddr::new(addr), flags);
+        entry
+    }
+
+    #[test]
+    fn recursive_table_addresses() {
+        let addr = VirtAddr::new(0xdeadbeaf000);
+        assert_eq!(table_address(addr, 4).as_u64(), 0xffff_ffff_ffff_f000);
+        // p4 index of 0xdeadbeaf000 is 27, p3 index 427, p2 index 223
+        assert_eq!(table_address(addr, 3).as_u64(), 0xffff_ffff_ffe0_0000 | 27 << 12);
+        assert_eq!(table_address(addr, 2).as_u64(), 0xffff_ffff_c000_0000 | 27 << 21 | 427 << 12);
+        assert_eq!(table_address(addr, 1).as_u64(),
+                   0xffff_ff80_0000_0000 | 27 << 30 | 427 << 21 | 223 << 12);
+    }
+
+    #[test]
+    fn walk_stops_at_missing_entry() {
+        let addr = VirtAddr::new(0xdeadbeaf000);
+        let mut p4 = Box::new(PageTable::new());
+        let mut p3 = Box::new(PageTable::new());
+        let p2 = Box::new(PageTable::new());
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        p4[addr.p4_index()] = entry(0x1000, flags);
+        p3[addr.p3_index()] = entry(0x2000, flags);
+
+        let walk = PageWalk::walk_with(addr, |level| match level {
+            4 => &p4,
+            3 => &p3,
+            2 => &p2,
+            _ => panic!("P1 of an unmapped P2 entry accessed"),
+        });
+        let levels: [u8; 3] = [4, 3, 2];
+        assert!(walk.steps().map(|step| step.level).eq(levels.iter().cloned()));
+        assert!(!walk.is_mapped());
+        assert!(walk.to_string().contains("P2[223] unused"));
+    }
+
+    #[test]
+    fn walk_stops_at_huge_page() {
+        let addr = VirtAddr::new(0x20_0000);
+        let mut p4 = Box::new(PageTable::new());
+        let mut p3 = Box::new(PageTable::new());
+        let mut p2 = Box::new(PageTable::new());
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        p4[0] = entry(0x1000, flags);
+        p3[0] = entry(0x2000, flags);
+        p2[1] = entry(0x20_0000, flags | PageTableFlags::HUGE_PAGE);
+
+        let walk = PageWalk::walk_with(addr, |level| match level {
+            4 => &p4,
+            3 => &p3,
+            2 => &p2,
+            _ => panic!("P1 of a huge page accessed"),
+        });
+        assert_eq!(walk.steps().count(), 3);
+        assert!(walk.is_mapped());
+    }
+
+    #[test]
+    fn describes_error_codes() {
+        let code = PageFaultErrorCode::CAUSED_BY_WRITE;
+        assert_eq!(ErrorCodeDescription(code).to_string(), "0x2: page not present, write, kernel mode");
+
+        let code = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::USER_MODE
+            | PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::MALFORMED_TABLE;
+        assert_eq!(ErrorCodeDescription(code).to_string(),
+                   "0x1d: protection violation, instruction fetch, user mode, \
+                    reserved bit set in page table entry");
+    }
+}
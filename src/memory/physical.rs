@@ -0,0 +1,389 @@
+#[cfg(any(feature = "map_physical_memory", test))]
+use core::cmp;
+#[cfg(any(feature = "map_physical_memory", test))]
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FlagUpdateError, FrameAllocator, FrameError, MapToError, Page,
+                                 PageTable, PageTableEntry, PageTableFlags, PhysFrame, Size4KiB,
+                                 UnmapError};
+use memory::Translation;
+use memory::page_fault::PageWalk;
+
+/// Virtual address at which physical address 0 is mapped when the kernel is built with
+/// the `map_physical_memory` feature (P4 index 224).
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0x_7000_0000_0000;
+/// Physical memory beyond this isn't mapped, it is what a single P4 entry covers.
+pub const MAX_MAPPED_MEMORY: u64 = 512 << 30;
+
+// End of the mapped physical memory, 0 as long as nothing is mapped
+static MAPPED_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the virtual address through which the physical address can be accessed.
+///
+/// Returns `None` if physical memory isn't mapped, which is the case without the
+/// `map_physical_memory` feature, or if the address isn't part of the mapping, e.g.
+/// device memory.
+pub fn phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    if addr.as_u64() >= MAPPED_END.load(Ordering::Acquire) as u64 {
+        return None;
+    }
+    let virt_addr = VirtAddr::new(PHYSICAL_MEMORY_OFFSET + addr.as_u64());
+    // unsafe: the recursive mapping is set up before anything runs
+    if unsafe { PageWalk::new(virt_addr) }.is_mapped() {
+        Some(virt_addr)
+    } else {
+        None
+    }
+}
+
+/// Maps the RAM and ACPI regions of the memory map at `PHYSICAL_MEMORY_OFFSET`, using
+/// 2MiB pages where possible. Returns the number of bytes mapped.
+///
+/// The mapping is cacheable and not executable. Device memory and reserved ranges are
+/// left out, so they aren't aliased as cacheable memory. They must be accessed through
+/// their own mapping with the right caching flags instead.
+///
+/// Must be called after `init_kernel_memory`. Address spaces share the mapping, also
+/// those created before.
+#[cfg(feature = "map_physical_memory")]
+pub fn init(memory_map: &::bootloader::bootinfo::MemoryMap) -> Result<u64, ::memory::MemoryError> {
+    use memory::{vma, KERNEL_MEMORY};
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    // the whole P4 entry is reserved, so nothing else ends up behind the mapping
+    vma::reserve("physical memory", VirtAddr::new(PHYSICAL_MEMORY_OFFSET), MAX_MAPPED_MEMORY,
+                 flags, vma::Backing::Mapped)
+        .expect("physical memory mapping overlaps a reserved area");
+
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+    let mut mapped = 0;
+    let mut end = 0;
+    // adjacent regions are mapped together, so 2MiB pages can span them. The memory
+    // map is sorted by address
+    let mut run: Option<Range<u64>> = None;
+    let ranges = memory_map.iter()
+        .filter(|region| is_memory(region.region_type))
+        .map(|region| region.range.start_addr()..cmp::min(region.range.end_addr(), MAX_MAPPED_MEMORY))
+        .filter(|range| range.start < range.end);
+    for range in ranges.map(Some).chain(Some(None)) {
+        match (run.take(), range) {
+            (Some(current), Some(ref next)) if next.start <= current.end => {
+                run = Some(current.start..cmp::max(current.end, next.end));
+                continue;
+            }
+            (Some(current), next) => {
+                map_run(current.clone(), flags, memory)?;
+                mapped += current.end - current.start;
+                end = current.end;
+                run = next;
+            }
+            (None, next) => run = next,
+        }
+    }
+    MAPPED_END.store(end as usize, Ordering::Release);
+    Ok(mapped)
+}
+
+// Returns whether regions of the type are memory, which can be mapped cacheable: RAM,
+// including the kernel's and bootloader's, and the ACPI tables
+#[cfg(feature = "map_physical_memory")]
+fn is_memory(region_type: ::bootloader::bootinfo::MemoryRegionType) -> bool {
+    use bootloader::bootinfo::MemoryRegionType::*;
+
+    match region_type {
+        Usable | InUse | AcpiReclaimable | AcpiNvs | Kernel | KernelStack | PageTable
+        | Bootloader | FrameZero | BootInfo | Package => true,
+        _ => false,
+    }
+}
+
+// Maps a page aligned physical range at the offset, with 2MiB pages where the range
+// covers them completely
+#[cfg(feature = "map_physical_memory")]
+fn map_run(range: Range<u64>, flags: PageTableFlags, memory: &mut ::memory::KernelMemory)
+           -> Result<(), ::memory::MemoryError> {
+    use x86_64::structures::paging::{PageSize, Size2MiB};
+    use memory::map_contiguous;
+
+    let [head, middle, tail] = split_at_huge_pages(range);
+    // unsafe: the mapping only aliases memory, all accesses go through phys_to_virt
+    unsafe {
+        for part in [head, tail].iter().filter(|part| part.start < part.end) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(PHYSICAL_MEMORY_OFFSET + part.start));
+            let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(part.start));
+            map_contiguous(page, frame, (part.end - part.start) / Size4KiB::SIZE, flags,
+                           &mut memory.page_table, &mut memory.frame_allocator)?;
+        }
+        if middle.start < middle.end {
+            let page = Page::<Size2MiB>::containing_address(VirtAddr::new(PHYSICAL_MEMORY_OFFSET + middle.start));
+            let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(middle.start));
+            map_contiguous(page, frame, (middle.end - middle.start) / Size2MiB::SIZE, flags,
+                           &mut memory.page_table, &mut memory.frame_allocator)?;
+        }
+    }
+    Ok(())
+}
+
+// Splits a page aligned range into the part before the first 2MiB boundary, the 2MiB
+// aligned middle and the part after it. Parts may be empty
+#[cfg(any(feature = "map_physical_memory", test))]
+fn split_at_huge_pages(range: Range<u64>) -> [Range<u64>; 3] {
+    const HUGE_PAGE_SIZE: u64 = 2 << 20;
+    let middle_start = cmp::min((range.start + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1), range.end);
+    let middle_end = cmp::max(range.end & !(HUGE_PAGE_SIZE - 1), middle_start);
+    [range.start..middle_start, middle_start..middle_end, middle_end..range.end]
+}
+
+/// A pending TLB flush for a page modified by `OffsetPageTable`, like `MapperFlush`
+/// which can only be created by the x86_64 crate.
+#[must_use = "page table changes must be flushed or ignored"]
+pub struct OffsetFlush(Page);
+
+impl OffsetFlush {
+    /// Flushes the page from the TLB, so the change takes effect.
+    pub fn flush(self) {
+        tlb::flush(self.0.start_address());
+    }
+
+    /// Skips the flush, e.g. when the page table isn't active.
+    pub fn ignore(self) {}
+}
+
+/// A mapper that reaches the page tables through a linear mapping of physical memory
+/// instead of the recursive entry, so it works for inactive P4 tables as well.
+///
+/// Maps 4KiB pages only, but translates through huge pages.
+pub struct OffsetPageTable<'a> {
+    p4: &'a mut PageTable,
+    // virtual address at which physical address 0 is mapped
+    offset: u64,
+}
+
+impl<'a> OffsetPageTable<'a> {
+    /// Creates a mapper for the P4 table whose tables are mapped at `offset` plus their
+    /// physical address.
+    ///
+    /// Unsafe: all tables must be mapped at the offset, and no other reference may
+    /// modify them while the mapper exists.
+    pub unsafe fn new(p4: &'a mut PageTable, offset: u64) -> Self {
+        OffsetPageTable { p4, offset }
+    }
+
+    /// Creates a mapper for the P4 table in the frame, using the kernel's physical
+    /// memory mapping. Returns `None` if physical memory isn't mapped.
+    ///
+    /// Unsafe for the same reasons as `new`.
+    pub unsafe fn for_frame(p4_frame: PhysFrame) -> Option<OffsetPageTable<'static>> {
+        let p4 = &mut *phys_to_virt(p4_frame.start_address())?.as_mut_ptr::<PageTable>();
+        Some(OffsetPageTable::new(p4, PHYSICAL_MEMORY_OFFSET))
+    }
+
+    /// Creates a mapper for the active P4 table, see `for_frame`.
+    pub unsafe fn active() -> Option<OffsetPageTable<'static>> {
+        OffsetPageTable::for_frame(Cr3::read().0)
+    }
+
+    /// Translates the virtual address, following huge page entries at P3 and P2.
+    pub fn translate(&self, addr: VirtAddr) -> Option<Translation> {
+        let walk = PageWalk::walk_with(addr, |level| self.walk_table(addr, level));
+        Translation::from_walk(addr, &walk)
+    }
+
+    /// Maps the page to the frame, creating missing page tables with the allocator.
+    ///
+    /// Unsafe: the caller must ensure the frame isn't aliased in a way that breaks
+    /// memory safety.
+    pub unsafe fn map_to<A>(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags,
+                            frame_allocator: &mut A) -> Result<OffsetFlush, MapToError>
+        where A: FrameAllocator<Size4KiB>
+    {
+        let addr = page.start_address();
+        let offset = self.offset;
+        let mut table: &mut PageTable = &mut *self.p4;
+        for &index in [addr.p4_index(), addr.p3_index(), addr.p2_index()].iter() {
+            let next = next_table_create(offset, &mut table[index], frame_allocator)?;
+            table = next;
+        }
+
+        let entry = &mut table[addr.p1_index()];
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped);
+        }
+        entry.set_frame(frame, flags);
+        Ok(OffsetFlush(page))
+    }
+
+    /// Removes the mapping of the page and returns its frame.
+    pub fn unmap(&mut self, page: Page) -> Result<(PhysFrame, OffsetFlush), UnmapError> {
+        let p1 = self.p1_mut(page.start_address())?;
+        let entry = &mut p1[page.start_address().p1_index()];
+        let frame = entry.frame().map_err(|err| match err {
+            FrameError::FrameNotPresent => UnmapError::PageNotMapped,
+            FrameError::HugeFrame => UnmapError::ParentEntryHugePage,
+        })?;
+        entry.set_unused();
+        Ok((frame, OffsetFlush(page)))
+    }
+
+    /// Replaces the flags of a mapped page.
+    pub fn update_flags(&mut self, page: Page, flags: PageTableFlags)
+                        -> Result<OffsetFlush, FlagUpdateError> {
+        let p1 = self.p1_mut(page.start_address()).map_err(|_| FlagUpdateError::PageNotMapped)?;
+        let entry = &mut p1[page.start_address().p1_index()];
+        if entry.is_unused() {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+        entry.set_flags(flags);
+        Ok(OffsetFlush(page))
+    }
+
+    // Returns the table of the given level used to translate the address. Only called
+    // by PageWalk for levels whose parent entries are present and not huge
+    fn walk_table(&self, addr: VirtAddr, level: u8) -> &PageTable {
+        let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+        let mut table: &PageTable = &*self.p4;
+        for &index in indices[..4 - level as usize].iter() {
+            // unsafe: guaranteed by the creator of the mapper
+            table = unsafe { table_at(self.offset, table[index].addr()) };
+        }
+        table
+    }
+
+    // Returns the P1 table of the address without creating missing tables
+    fn p1_mut(&mut self, addr: VirtAddr) -> Result<&mut PageTable, UnmapError> {
+        let offset = self.offset;
+        let mut table: &mut PageTable = &mut *self.p4;
+        for &index in [addr.p4_index(), addr.p3_index(), addr.p2_index()].iter() {
+            let next = {
+                let entry = &table[index];
+                if entry.is_unused() {
+                    return Err(UnmapError::PageNotMapped);
+                }
+                if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    return Err(UnmapError::ParentEntryHugePage);
+                }
+                entry.addr()
+            };
+            // unsafe: guaranteed by the creator of the mapper
+            table = unsafe { table_at(offset, next) };
+        }
+        Ok(table)
+    }
+}
+
+// Returns the table at the physical address.
+// Unsafe: the table must be mapped at `offset` and not be referenced elsewhere
+unsafe fn table_at<'b>(offset: u64, addr: PhysAddr) -> &'b mut PageTable {
+    &mut *((offset + addr.as_u64()) as *mut PageTable)
+}
+
+// Returns the table the entry points to. An unused entry gets a new zeroed table,
+// like RecursivePageTable only with PRESENT and WRITABLE set.
+// Unsafe: the table must be mapped at `offset`, new frames are expected to be as well
+unsafe fn next_table_create<'b, A>(offset: u64, entry: &mut PageTableEntry, frame_allocator: &mut A)
+                                   -> Result<&'b mut PageTable, MapToError>
+    where A: FrameAllocator<Size4KiB>
+{
+    if entry.is_unused() {
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        let table = table_at(offset, frame.start_address());
+        table.zero();
+        Ok(table)
+    } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        Err(MapToError::ParentEntryHugePage)
+    } else {
+        Ok(table_at(offset, entry.addr()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    // Hands out heap allocated tables, whose addresses serve as physical addresses
+    // with an offset of 0
+    struct TableAllocator(Vec<Box<PageTable>>);
+
+    impl FrameAllocator<Size4KiB> for TableAllocator {
+        fn allocate_frame(&mut self) -> Option<PhysFrame> {
+            let mut table = Box::new(PageTable::new());
+            let addr = PhysAddr::new(&mut *table as *mut PageTable as u64);
+            self.0.push(table);
+            Some(PhysFrame::containing_address(addr))
+        }
+    }
+
+    fn page(addr: u64) -> Page {
+        Page::containing_address(VirtAddr::new(addr))
+    }
+
+    #[test]
+    fn maps_translates_and_unmaps() {
+        let mut p4 = Box::new(PageTable::new());
+        let mut allocator = TableAllocator(Vec::new());
+        let mut mapper = unsafe { OffsetPageTable::new(&mut p4, 0) };
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        unsafe {
+            mapper.map_to(page(0xdeadbeaf000), frame, flags, &mut allocator).unwrap().ignore();
+            match mapper.map_to(page(0xdeadbeaf000), frame, flags, &mut allocator) {
+                Err(MapToError::PageAlreadyMapped) => {}
+                _ => panic!("mapped the page twice"),
+            }
+        }
+        assert_eq!(allocator.0.len(), 3);
+        let translation = mapper.translate(VirtAddr::new(0xdeadbeaf123)).unwrap();
+        assert_eq!(translation, Translation { phys_addr: PhysAddr::new(0x10_0123), page_size: 4096 });
+        assert_eq!(mapper.translate(VirtAddr::new(0xdeadbeb0000)), None);
+
+        mapper.update_flags(page(0xdeadbeaf000), PageTableFlags::PRESENT).unwrap().ignore();
+        let (unmapped, flush) = mapper.unmap(page(0xdeadbeaf000)).unwrap();
+        flush.ignore();
+        assert_eq!(unmapped, frame);
+        assert_eq!(mapper.translate(VirtAddr::new(0xdeadbeaf000)), None);
+        match mapper.unmap(page(0xdeadbeaf000)) {
+            Err(UnmapError::PageNotMapped) => {}
+            _ => panic!("unmapped a missing page"),
+        }
+    }
+
+    #[test]
+    fn splits_ranges_at_huge_pages() {
+        const MIB: u64 = 1 << 20;
+        assert_eq!(split_at_huge_pages(MIB..7 * MIB), [MIB..2 * MIB, 2 * MIB..6 * MIB, 6 * MIB..7 * MIB]);
+        assert_eq!(split_at_huge_pages(0..4 * MIB), [0..0, 0..4 * MIB, 4 * MIB..4 * MIB]);
+        // no 2MiB page fits
+        assert_eq!(split_at_huge_pages(0x1000..0x9f000), [0x1000..0x9f000, 0x9f000..0x9f000,
+                                                          0x9f000..0x9f000]);
+        assert_eq!(split_at_huge_pages(MIB..3 * MIB), [MIB..2 * MIB, 2 * MIB..2 * MIB, 2 * MIB..3 * MIB]);
+    }
+
+    #[test]
+    fn translates_huge_pages() {
+        let mut p4 = Box::new(PageTable::new());
+        let mut allocator = TableAllocator(Vec::new());
+        let p3 = allocator.allocate_frame().unwrap();
+        p4[0].set_frame(p3, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        unsafe { table_at(0, p3.start_address())[1].set_addr(PhysAddr::new(0x4000_0000),
+            PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE) };
+
+        let mut mapper = unsafe { OffsetPageTable::new(&mut p4, 0) };
+        let translation = mapper.translate(VirtAddr::new(0x4123_4567)).unwrap();
+        assert_eq!(translation.phys_addr, PhysAddr::new(0x4123_4567));
+        assert_eq!(translation.page_size, 1 << 30);
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(0x10_0000));
+        match unsafe { mapper.map_to(page(0x4000_0000), frame, PageTableFlags::PRESENT, &mut allocator) } {
+            Err(MapToError::ParentEntryHugePage) => {}
+            _ => panic!("mapped a page inside a huge page"),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, RecursivePageTable, Size4KiB,
+                                 Size2MiB, Size1GiB};
+use memory::inspect::{self, MappedRange};
+use memory::protect_range;
+
+extern "C" {
+    // Defined by the linker: the ELF header at the start of the kernel image and the
+    // end of the code. Only their addresses are used
+    static __ehdr_start: u8;
+    static etext: u8;
+}
+
+/// Returns the range of the kernel image containing its code, aligned to pages.
+///
+/// Read-only data in front of the code is part of the range as well, it keeps the
+/// NO_EXECUTE flag set by the bootloader.
+pub fn kernel_code() -> (VirtAddr, VirtAddr) {
+    // unsafe: the statics are never read
+    let (start, end) = unsafe { (VirtAddr::from_ptr(&__ehdr_start), VirtAddr::from_ptr(&etext)) };
+    (start.align_down(Size4KiB::SIZE), end.align_up(Size4KiB::SIZE))
+}
+
+/// Enables the NO_EXECUTE page table flag (EFER.NXE) and makes read-only pages
+/// read-only for the kernel as well (CR0.WP).
+///
+/// Must be called before any page table entry has the NO_EXECUTE flag set.
+pub fn enable_nx_and_write_protect() {
+    // unsafe: only enables additional protection, which is ok as long as the kernel
+    // doesn't write to read-only or execute non executable pages
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// Returns the flags a mapping must have so that it is either writable or executable,
+/// but never both.
+///
+/// Executable code stays executable but loses WRITABLE. Everything else, e.g. data,
+/// stacks and the heap, becomes non executable.
+pub fn wx_flags(is_code: bool, flags: PageTableFlags) -> PageTableFlags {
+    if is_code && !flags.contains(PageTableFlags::NO_EXECUTE) {
+        flags - PageTableFlags::WRITABLE
+    } else {
+        flags | PageTableFlags::NO_EXECUTE
+    }
+}
+
+// Splits the range into the parts before, inside and after the code range. Huge pages
+// aren't split, a huge page overlapping the code counts as code
+fn split_at_code(range: &MappedRange, code_start: VirtAddr, code_end: VirtAddr)
+                 -> [Option<(MappedRange, bool)>; 3]
+{
+    if range.page_size != Size4KiB::SIZE {
+        let is_code = range.start < code_end && code_start < range.end();
+        return [Some((*range, is_code)), None, None];
+    }
+
+    let bounds = [range.start, code_start, code_end, range.end()];
+    let mut parts = [None, None, None];
+    for i in 0..3 {
+        // clamp the bounds of the part into the range
+        let start = core::cmp::min(core::cmp::max(bounds[i], range.start), range.end());
+        let end = core::cmp::max(core::cmp::min(bounds[i + 1], range.end()), range.start);
+        if start < end {
+            let part = MappedRange {
+                start,
+                phys_start: range.phys_start + (start - range.start),
+                size: end - start,
+                ..*range
+            };
+            parts[i] = Some((part, i == 1));
+        }
+    }
+    parts
+}
+
+// Updates the flags of all pages in the range, using the range's page size
+fn protect_mapped_range(range: &MappedRange, flags: PageTableFlags,
+                        page_table: &mut RecursivePageTable) -> u64 {
+    fn pages<S: PageSize>(range: &MappedRange) -> ::x86_64::structures::paging::PageRange<S> {
+        Page::range(Page::containing_address(range.start), Page::containing_address(range.end()))
+    }
+
+    // the range is updated with the size of the pages mapping it, so there are no
+    // larger pages containing them
+    let result = if range.page_size == Size1GiB::SIZE {
+        protect_range(pages::<Size1GiB>(range), flags, page_table)
+    } else if range.page_size == Size2MiB::SIZE {
+        protect_range(pages::<Size2MiB>(range), flags, page_table)
+    } else {
+        protect_range(pages::<Size4KiB>(range), flags, page_table)
+    };
+    result.expect("mapped range inside a huge page")
+}
+
+/// Walks all mappings of the active address space and enforces W^X: kernel code is
+/// made read-only and all other memory non executable.
+///
+/// Enables NXE and WP first. Returns the number of pages whose flags changed.
+pub fn enforce_wx(page_table: &mut RecursivePageTable) -> u64 {
+    enable_nx_and_write_protect();
+
+    let (code_start, code_end) = kernel_code();
+    let mut changed = 0;
+    // unsafe: the recursive entry is set up by the bootloader. Only flags of pages the
+    // iterator already returned are modified, so the walk isn't disturbed
+    for range in unsafe { inspect::active_mappings() } {
+        for part in split_at_code(&range, code_start, code_end).iter() {
+            if let Some((ref part, is_code)) = *part {
+                let flags = wx_flags(is_code, part.flags);
+                if flags != part.flags {
+                    changed += protect_mapped_range(part, flags, page_table);
+                }
+            }
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use x86_64::PhysAddr;
+
+    fn range(start: u64, size: u64, page_size: u64) -> MappedRange {
+        MappedRange {
+            start: VirtAddr::new(start),
+            phys_start: PhysAddr::new(0x10_0000 + start),
+            size,
+            page_size,
+            flags: PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        }
+    }
+
+    #[test]
+    fn code_is_never_writable() {
+        let rwx = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        assert_eq!(wx_flags(true, rwx), PageTableFlags::PRESENT);
+        assert_eq!(wx_flags(false, rwx), rwx | PageTableFlags::NO_EXECUTE);
+
+        // read-only data next to the code stays non executable
+        let rodata = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        assert_eq!(wx_flags(true, rodata), rodata);
+        assert_eq!(wx_flags(false, PageTableFlags::PRESENT), rodata);
+    }
+
+    #[test]
+    fn splits_ranges_at_code_bounds() {
+        let code_start = VirtAddr::new(0x20_2000);
+        let code_end = VirtAddr::new(0x20_5000);
+
+        let parts = split_at_code(&range(0x20_0000, 0x8000, 0x1000), code_start, code_end);
+        let (before, is_code) = parts[0].unwrap();
+        assert_eq!((before.start.as_u64(), before.size, is_code), (0x20_0000, 0x2000, false));
+        let (code, is_code) = parts[1].unwrap();
+        assert_eq!((code.start.as_u64(), code.size, is_code), (0x20_2000, 0x3000, true));
+        assert_eq!(code.phys_start, PhysAddr::new(0x30_2000));
+        let (after, is_code) = parts[2].unwrap();
+        assert_eq!((after.start.as_u64(), after.size, is_code), (0x20_5000, 0x3000, false));
+
+        let parts = split_at_code(&range(0x40_0000, 0x1000, 0x1000), code_start, code_end);
+        assert!(parts[0].is_none() && parts[1].is_none());
+        assert_eq!(parts[2].unwrap().1, false);
+
+        let parts = split_at_code(&range(0x20_0000, 0x20_0000, 0x20_0000), code_start, code_end);
+        assert_eq!(parts[0].unwrap().1, true);
+        assert!(parts[1].is_none() && parts[2].is_none());
+    }
+}
@@ -0,0 +1,135 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageRange,
+                                 PageTableFlags, PhysFrame};
+use memory::{MemoryError, KERNEL_MEMORY};
+use memory::address_space::AddressSpace;
+
+/// Marks pages of a shared memory object. Forking keeps them writable and shared instead
+/// of making them copy-on-write, so writes stay visible to all address spaces.
+pub const SHARED_MEMORY: PageTableFlags = PageTableFlags::BIT_10;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+pub enum SharedMemoryError {
+    /// Not enough free frames for the object.
+    FrameAllocationFailed,
+    /// An object with the name already exists.
+    NameInUse,
+    /// No object with the name exists.
+    NotFound,
+    /// Mapping the object into an address space failed.
+    MapFailed(MemoryError),
+}
+
+lazy_static! {
+    // Named objects stay alive until they are unlinked, even without other handles
+    static ref NAMED_OBJECTS: Mutex<BTreeMap<String, SharedMemory>> = Mutex::new(BTreeMap::new());
+}
+
+// Owns one reference to each frame, every mapping of the object holds another one
+struct Frames(Vec<PhysFrame>);
+
+impl Drop for Frames {
+    fn drop(&mut self) {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        for &frame in self.0.iter() {
+            memory.frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+/// A handle to physical memory that can be mapped into several address spaces at once.
+///
+/// Handles are cloned to share the object. Its frames are freed once the last handle is
+/// dropped and the last mapping is gone, whichever comes last. Dropping the last handle
+/// takes the `KERNEL_MEMORY` lock, so it must not happen while the lock is held.
+#[derive(Clone)]
+pub struct SharedMemory {
+    frames: Arc<Frames>,
+}
+
+impl SharedMemory {
+    /// Creates an anonymous object of `size` bytes, rounded up to whole pages, backed
+    /// by zeroed frames.
+    pub fn new(size: u64) -> Result<SharedMemory, SharedMemoryError> {
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut frames = Frames(Vec::with_capacity(pages as usize));
+
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+        for _ in 0..pages {
+            match memory.frame_allocator.allocate_frame() {
+                Some(frame) => {
+                    memory.zero_frame(frame);
+                    frames.0.push(frame);
+                }
+                None => {
+                    // Frames is dropped below, after the lock is released
+                    drop(kernel_memory);
+                    return Err(SharedMemoryError::FrameAllocationFailed);
+                }
+            }
+        }
+        Ok(SharedMemory { frames: Arc::new(frames) })
+    }
+
+    /// Creates an object that can be opened by name until it is unlinked.
+    pub fn create(name: &str, size: u64) -> Result<SharedMemory, SharedMemoryError> {
+        // checked before allocating, and again below since the lock isn't held meanwhile
+        if NAMED_OBJECTS.lock().contains_key(name) {
+            return Err(SharedMemoryError::NameInUse);
+        }
+        let object = SharedMemory::new(size)?;
+
+        let mut named_objects = NAMED_OBJECTS.lock();
+        if named_objects.contains_key(name) {
+            drop(named_objects);
+            return Err(SharedMemoryError::NameInUse);
+        }
+        named_objects.insert(String::from(name), object.clone());
+        Ok(object)
+    }
+
+    /// Returns a handle to the named object.
+    pub fn open(name: &str) -> Result<SharedMemory, SharedMemoryError> {
+        NAMED_OBJECTS.lock().get(name).cloned().ok_or(SharedMemoryError::NotFound)
+    }
+
+    /// Removes the name. The object lives on as long as handles or mappings exist.
+    pub fn unlink(name: &str) -> Result<(), SharedMemoryError> {
+        // the handle is dropped after the registry lock is released
+        let object = NAMED_OBJECTS.lock().remove(name);
+        object.map(|_| ()).ok_or(SharedMemoryError::NotFound)
+    }
+
+    /// Size of the object in bytes.
+    pub fn size(&self) -> u64 {
+        self.frames.0.len() as u64 * PAGE_SIZE
+    }
+
+    /// The frames backing the object, in order.
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames.0
+    }
+
+    /// Maps the whole object into the address space, starting at the user page `start`.
+    ///
+    /// `flags` are applied to every page, USER_ACCESSIBLE and `SHARED_MEMORY` are added.
+    pub fn map(&self, address_space: &mut AddressSpace, start: Page, flags: PageTableFlags)
+               -> Result<(), SharedMemoryError> {
+        address_space.map_shared_frames(start, self.frames(), flags | SHARED_MEMORY)
+            .map_err(SharedMemoryError::MapFailed)
+    }
+
+    /// Removes the mapping of the object starting at `start` from the address space.
+    pub fn unmap(&self, address_space: &mut AddressSpace, start: Page) -> Result<(), MemoryError> {
+        let end = start + self.frames.0.len() as u64;
+        address_space.unmap_user_range(PageRange { start, end })
+    }
+}
@@ -0,0 +1,126 @@
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageRange, PageTableFlags, Size4KiB};
+use memory::{KERNEL_MEMORY, MemoryError, map_zeroed_page, unmap_range, stats};
+
+/// Virtual region reserved for kernel stacks (1 GiB).
+pub const KERNEL_STACKS_START: u64 = 0x_5000_0000_0000;
+pub const KERNEL_STACKS_SIZE: u64 = 0x4000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+/// A kernel stack. The page below `bottom` is an unmapped guard page, so overflowing
+/// the stack causes a page fault instead of overwriting other memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    top: VirtAddr,
+    bottom: VirtAddr,
+}
+
+impl Stack {
+    /// Highest address of the stack. x86 stacks grow downwards, so this is the
+    /// initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+
+    fn pages(&self) -> PageRange {
+        Page::range(Page::containing_address(self.bottom), Page::containing_address(self.top))
+    }
+}
+
+/// Hands out stacks from a virtual address range, each one preceded by a guard page.
+///
+/// Addresses are never reused: a freed stack's frames are returned, but its range
+/// stays unused so stale pointers into it keep faulting.
+pub struct StackAllocator {
+    next: u64,
+    end: u64,
+}
+
+impl StackAllocator {
+    pub const fn new(start: u64, end: u64) -> Self {
+        StackAllocator { next: start, end }
+    }
+
+    /// Maps a stack of `size_in_pages` pages below a new guard page.
+    ///
+    /// Fails if the size is 0, the range is exhausted or not enough frames are left.
+    pub fn alloc_stack<A>(
+        &mut self,
+        size_in_pages: u64,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut A,
+    ) -> Result<Stack, MemoryError>
+        where A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>
+    {
+        if size_in_pages == 0 {
+            return Err(MemoryError::EmptyStack);
+        }
+
+        // the guard page is the first page of the slot and simply never mapped
+        let bottom = self.next + PAGE_SIZE;
+        let top = bottom + size_in_pages * PAGE_SIZE;
+        if top > self.end {
+            return Err(MemoryError::AddressRangeExhausted);
+        }
+        let stack = Stack { top: VirtAddr::new(top), bottom: VirtAddr::new(bottom) };
+
+        // stacks hold data only, so they are never executable
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for page in stack.pages() {
+            if let Err(err) = map_zeroed_page(page, flags, mapper, frame_allocator) {
+                // undo the pages mapped so far
+                let partial = Stack { top: page.start_address(), bottom: stack.bottom };
+                free_stack(partial, mapper, frame_allocator);
+                return Err(err);
+            }
+        }
+
+        self.next = top;
+        Ok(stack)
+    }
+}
+
+/// Unmaps the stack and returns its frames to the frame allocator.
+///
+/// The stack must not be in use anymore.
+pub fn free_stack<A>(stack: Stack, mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut A)
+    where A: FrameDeallocator<Size4KiB>
+{
+    unmap_range(stack.pages(), mapper, frame_allocator).expect("stack pages not unmappable");
+}
+
+// Allocator for all kernel stacks, e.g. interrupt stacks and future thread stacks
+static STACK_ALLOCATOR: Mutex<StackAllocator> =
+    Mutex::new(StackAllocator::new(KERNEL_STACKS_START, KERNEL_STACKS_START + KERNEL_STACKS_SIZE));
+
+/// Allocates a guarded kernel stack using the kernel's page table.
+///
+/// Requires `memory::init_kernel_memory` to be called before.
+pub fn alloc_kernel_stack(size_in_pages: u64) -> Result<Stack, MemoryError> {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+    let stack = STACK_ALLOCATOR.lock()
+        .alloc_stack(size_in_pages, &mut memory.page_table, &mut memory.frame_allocator);
+    if stack.is_ok() {
+        stats::KERNEL_STACK_PAGES.fetch_add(size_in_pages as usize, Ordering::Relaxed);
+    }
+    stack
+}
+
+/// Frees a stack returned by `alloc_kernel_stack`.
+pub fn free_kernel_stack(stack: Stack) {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    let memory = kernel_memory.as_mut().expect("kernel memory not initialized");
+    free_stack(stack, &mut memory.page_table, &mut memory.frame_allocator);
+    stats::KERNEL_STACK_PAGES.fetch_sub((stack.size() / PAGE_SIZE) as usize, Ordering::Relaxed);
+}
@@ -0,0 +1,301 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use allocator::{self, ALLOCATOR};
+use allocator::slab::SlabAllocator;
+use memory::inspect::Size;
+use memory::{KernelMemory, KERNEL_MEMORY};
+
+const FRAME_SIZE: u64 = 4096;
+// Region types summed up separately. The bootloader has 14, the regions of any type
+// beyond are summed up as other types
+const REGION_TYPES: usize = 16;
+
+/// Frames currently used for page tables. The mappers never free page tables, only
+/// dropped address spaces do.
+pub static PAGE_TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+/// Pages currently mapped for kernel stacks, guard pages excluded.
+pub static KERNEL_STACK_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Wraps the frame allocator passed to `Mapper::map_to`, which only uses it to create
+/// page tables, to count the page table frames.
+pub struct PageTableAllocator<'a, A: 'a>(pub &'a mut A);
+
+impl<'a, A> FrameAllocator<Size4KiB> for PageTableAllocator<'a, A>
+    where A: FrameAllocator<Size4KiB>
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.0.allocate_frame();
+        if frame.is_some() {
+            PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
+        frame
+    }
+}
+
+/// Bytes of physical memory per region type, as reported by the bootloader.
+///
+/// Only `REGION_TYPES` types are told apart, the regions of further types are summed
+/// up together, see `other`.
+pub struct RegionTotals {
+    totals: [Option<(MemoryRegionType, u64)>; REGION_TYPES],
+    // bytes of the regions whose type didn't get a slot
+    other: u64,
+}
+
+impl RegionTotals {
+    pub fn new(memory_map: &MemoryMap) -> Self {
+        let mut totals = RegionTotals { totals: [None; REGION_TYPES], other: 0 };
+        for region in memory_map.iter() {
+            totals.add(region.region_type, region.range.end_addr() - region.range.start_addr());
+        }
+        totals
+    }
+
+    fn add(&mut self, region_type: MemoryRegionType, bytes: u64) {
+        for slot in self.totals.iter_mut() {
+            match *slot {
+                Some((ref total_type, ref mut total)) if *total_type == region_type => {
+                    *total += bytes;
+                    return;
+                }
+                None => {
+                    *slot = Some((region_type, bytes));
+                    return;
+                }
+                _ => {}
+            }
+        }
+        self.other += bytes;
+    }
+
+    /// Iterates over the region types present in the memory map, in order of their
+    /// first occurrence.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (MemoryRegionType, u64)> + 'a {
+        self.totals.iter().filter_map(|total| *total)
+    }
+
+    /// Returns the bytes of the regions of the types left out by `iter`, since there
+    /// were too many types.
+    pub fn other(&self) -> u64 {
+        self.other
+    }
+
+    /// Returns the bytes of all regions together.
+    pub fn total(&self) -> u64 {
+        self.iter().map(|(_, bytes)| bytes).sum::<u64>() + self.other
+    }
+}
+
+/// A snapshot of the memory usage, printable as a `meminfo` report.
+pub struct MemInfo {
+    pub regions: RegionTotals,
+    pub usage: Usage,
+}
+
+/// The memory usage without the memory map.
+pub struct Usage {
+    // None if the kernel memory isn't initialized yet or was locked
+    pub frames: Option<FrameCounts>,
+    pub page_table_frames: usize,
+    pub kernel_stack_pages: usize,
+    // None if the heap was locked
+    pub heap: Option<HeapUsage>,
+}
+
+/// Frame counts of the kernel's frame allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCounts {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Bytes of the kernel heap by use.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapUsage {
+    // size of the linked list heap serving the large allocations, the slabs are
+    // mapped separately
+    pub size: u64,
+    pub slab_bytes: u64,
+    pub slab_used_bytes: u64,
+    pub large_bytes: u64,
+}
+
+fn frame_counts(memory: &KernelMemory) -> FrameCounts {
+    FrameCounts {
+        total: memory.frame_allocator.total_frames(),
+        used: memory.frame_allocator.used_frames(),
+        free: memory.frame_allocator.free_frames(),
+    }
+}
+
+fn heap_usage(heap: &SlabAllocator) -> HeapUsage {
+    HeapUsage {
+        size: allocator::HEAP_SIZE,
+        slab_bytes: heap.cache_stats()
+            .map(|stats| (stats.slabs * allocator::slab::SLAB_SIZE) as u64)
+            .sum(),
+        slab_used_bytes: heap.cache_stats()
+            .map(|stats| (stats.used_blocks * stats.block_size) as u64)
+            .sum(),
+        large_bytes: heap.fallback_stats().used_bytes as u64,
+    }
+}
+
+/// Collects the current memory usage.
+///
+/// Briefly takes the locks of the kernel memory and the heap, so it must not be called
+/// while either is held.
+pub fn meminfo(memory_map: &MemoryMap) -> MemInfo {
+    let usage = Usage {
+        frames: KERNEL_MEMORY.lock().as_ref().map(frame_counts),
+        page_table_frames: PAGE_TABLE_FRAMES.load(Ordering::Relaxed),
+        kernel_stack_pages: KERNEL_STACK_PAGES.load(Ordering::Relaxed),
+        heap: Some(heap_usage(&ALLOCATOR.lock())),
+    };
+    MemInfo { regions: RegionTotals::new(memory_map), usage }
+}
+
+/// Collects the memory usage without waiting for locks, leaving out the parts whose
+/// lock is held. Used when memory runs out, possibly while the locks are held.
+pub fn try_usage() -> Usage {
+    let frames = match KERNEL_MEMORY.try_lock() {
+        Some(kernel_memory) => kernel_memory.as_ref().map(frame_counts),
+        None => None,
+    };
+    Usage {
+        frames,
+        page_table_frames: PAGE_TABLE_FRAMES.load(Ordering::Relaxed),
+        kernel_stack_pages: KERNEL_STACK_PAGES.load(Ordering::Relaxed),
+        heap: ALLOCATOR.try_lock().map(|heap| heap_usage(&heap)),
+    }
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Physical memory:")?;
+        for (region_type, bytes) in self.regions.iter() {
+            // the size comes first since the region type's Debug output ignores padding
+            writeln!(f, "  {:>10} {:?}", Size(bytes), region_type)?;
+        }
+        if self.regions.other() > 0 {
+            writeln!(f, "  {:>10} Other types", Size(self.regions.other()))?;
+        }
+        writeln!(f, "  {:>10} Total", Size(self.regions.total()))?;
+        write!(f, "{}", self.usage)
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.frames {
+            Some(frames) => writeln!(f, "Frames: {} used, {} free of {} ({} used, {} free)",
+                                     frames.used, frames.free, frames.total,
+                                     Size(frames.used as u64 * FRAME_SIZE),
+                                     Size(frames.free as u64 * FRAME_SIZE))?,
+            None => writeln!(f, "Frames: unavailable")?,
+        }
+        writeln!(f, "Page tables: {} frames", self.page_table_frames)?;
+        writeln!(f, "Kernel stacks: {}", Size(self.kernel_stack_pages as u64 * FRAME_SIZE))?;
+        match self.heap {
+            Some(heap) => writeln!(f, "Heap: {} in slabs ({} used), {} of {} in large allocations",
+                                   Size(heap.slab_bytes), Size(heap.slab_used_bytes),
+                                   Size(heap.large_bytes), Size(heap.size)),
+            None => writeln!(f, "Heap: unavailable"),
+        }
+    }
+}
+
+/// Prints every region of the memory map and the totals per region type, one
+/// `key=value` line each, so the layout can be checked by scripts on the host.
+///
+/// The format is stable: lines start with `memory_map`, addresses are zero padded hex
+/// and sizes are in bytes.
+pub struct MemoryMapReport<'a>(pub &'a MemoryMap);
+
+impl<'a> fmt::Display for MemoryMapReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut regions = 0;
+        for region in self.0.iter() {
+            let (start, end) = (region.range.start_addr(), region.range.end_addr());
+            writeln!(f, "memory_map region start={:#018x} end={:#018x} size={} type={:?}",
+                     start, end, end - start, region.region_type)?;
+            regions += 1;
+        }
+
+        let totals = RegionTotals::new(self.0);
+        for (region_type, bytes) in totals.iter() {
+            writeln!(f, "memory_map type={:?} size={}", region_type, bytes)?;
+        }
+        if totals.other() > 0 {
+            writeln!(f, "memory_map type=Other size={}", totals.other())?;
+        }
+        let usable = totals.iter()
+            .filter(|&(region_type, _)| region_type == MemoryRegionType::Usable)
+            .map(|(_, bytes)| bytes)
+            .sum::<u64>();
+        writeln!(f, "memory_map total regions={} usable={} size={}", regions, usable, totals.total())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::string::ToString;
+    use bootloader::bootinfo::{MemoryRegion, FrameRange};
+
+    fn region(start_frame: u64, end_frame: u64, region_type: MemoryRegionType) -> MemoryRegion {
+        MemoryRegion {
+            range: FrameRange { start_frame_number: start_frame, end_frame_number: end_frame },
+            region_type,
+        }
+    }
+
+    #[test]
+    fn sums_regions_by_type() {
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(region(0, 1, MemoryRegionType::FrameZero));
+        memory_map.add_region(region(1, 100, MemoryRegionType::Usable));
+        memory_map.add_region(region(100, 110, MemoryRegionType::Kernel));
+        memory_map.add_region(region(200, 300, MemoryRegionType::Usable));
+
+        let totals = RegionTotals::new(&memory_map);
+        let usable = totals.iter().find(|&(region_type, _)| region_type == MemoryRegionType::Usable);
+        assert_eq!(usable, Some((MemoryRegionType::Usable, 199 * FRAME_SIZE)));
+        assert_eq!(totals.iter().count(), 3);
+        assert_eq!(totals.total(), 210 * FRAME_SIZE);
+    }
+
+    #[test]
+    fn sums_up_types_beyond_the_slots() {
+        let mut totals = RegionTotals::new(&MemoryMap::new());
+        // the bootloader has fewer region types than slots, so they are taken directly
+        for slot in totals.totals.iter_mut() {
+            *slot = Some((MemoryRegionType::Package, FRAME_SIZE));
+        }
+        totals.add(MemoryRegionType::Reserved, 10 * FRAME_SIZE);
+        totals.add(MemoryRegionType::AcpiNvs, 5 * FRAME_SIZE);
+
+        assert_eq!(totals.iter().count(), REGION_TYPES);
+        assert_eq!(totals.other(), 15 * FRAME_SIZE);
+        assert_eq!(totals.total(), (REGION_TYPES as u64 + 15) * FRAME_SIZE);
+    }
+
+    #[test]
+    fn memory_map_report_format() {
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(region(0, 1, MemoryRegionType::FrameZero));
+        memory_map.add_region(region(1, 16, MemoryRegionType::Usable));
+
+        let expected = "\
+memory_map region start=0x0000000000000000 end=0x0000000000001000 size=4096 type=FrameZero
+memory_map region start=0x0000000000001000 end=0x0000000000010000 size=61440 type=Usable
+memory_map type=FrameZero size=4096
+memory_map type=Usable size=61440
+memory_map total regions=2 usable=61440 size=65536
+";
+        assert_eq!(MemoryMapReport(&memory_map).to_string(), expected);
+    }
+}
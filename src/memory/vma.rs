@@ -0,0 +1,174 @@
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+// Maximum number of areas that can be reserved at the same time. The registry is a
+// fixed array so that the page fault handler never depends on the heap
+const MAX_AREAS: usize = 32;
+const PAGE_SIZE: u64 = 4096;
+
+/// Describes how the pages of an area receive their frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Pages are mapped by whoever reserved the area, e.g. the kernel heap.
+    Mapped,
+    /// Pages are mapped to a zeroed frame by the page fault handler on first access.
+    OnDemand,
+}
+
+/// A reserved range of virtual memory.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualMemoryArea {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    // exclusive
+    pub end: VirtAddr,
+    // flags the pages of the area are mapped with
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl VirtualMemoryArea {
+    pub fn new(name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags,
+               backing: Backing) -> Self {
+        VirtualMemoryArea { name, start, end: start + size, flags, backing }
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    fn overlaps(&self, other: &VirtualMemoryArea) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// Start or size of the area are not page aligned, or the area is empty.
+    InvalidArea,
+    /// The area overlaps the already reserved area with the given name.
+    Overlapping(&'static str),
+    /// No free slot is left in the registry.
+    RegistryFull,
+    /// No area starts at the given address.
+    NotFound,
+}
+
+/// Keeps track of the reserved virtual memory areas.
+pub struct VmaRegistry {
+    areas: [Option<VirtualMemoryArea>; MAX_AREAS],
+}
+
+impl VmaRegistry {
+    pub const fn new() -> Self {
+        VmaRegistry { areas: [None; MAX_AREAS] }
+    }
+
+    /// Reserves the area if it is page aligned and doesn't overlap any other area.
+    pub fn reserve(&mut self, area: VirtualMemoryArea) -> Result<(), VmaError> {
+        if area.start >= area.end
+            || !area.start.is_aligned(PAGE_SIZE)
+            || !area.end.is_aligned(PAGE_SIZE) {
+            return Err(VmaError::InvalidArea);
+        }
+        if let Some(other) = self.iter().find(|other| other.overlaps(&area)) {
+            return Err(VmaError::Overlapping(other.name));
+        }
+
+        let slot = self.areas.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmaError::RegistryFull)?;
+        *slot = Some(area);
+        Ok(())
+    }
+
+    /// Removes the area starting at `start` from the registry and returns it.
+    ///
+    /// Unmapping any pages of the area is up to the caller.
+    pub fn release(&mut self, start: VirtAddr) -> Result<VirtualMemoryArea, VmaError> {
+        let slot = self.areas.iter_mut()
+            .find(|slot| slot.map(|area| area.start) == Some(start))
+            .ok_or(VmaError::NotFound)?;
+        Ok(slot.take().unwrap())
+    }
+
+    /// Returns the area containing the address, if any.
+    pub fn find(&self, addr: VirtAddr) -> Option<VirtualMemoryArea> {
+        self.iter().find(|area| area.contains(addr)).cloned()
+    }
+
+    /// Iterates over all reserved areas, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &VirtualMemoryArea> {
+        self.areas.iter().filter_map(|slot| slot.as_ref())
+    }
+}
+
+/// The kernel's registry of reserved virtual memory areas.
+pub static VIRTUAL_MEMORY_AREAS: Mutex<VmaRegistry> = Mutex::new(VmaRegistry::new());
+
+/// Reserves an area in the kernel's registry.
+///
+/// `OnDemand` areas are mapped page by page by the page fault handler when they
+/// are first accessed, so reserving them doesn't use any physical memory.
+pub fn reserve(name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags,
+               backing: Backing) -> Result<(), VmaError> {
+    let area = VirtualMemoryArea::new(name, start, size, flags, backing);
+    VIRTUAL_MEMORY_AREAS.lock().reserve(area)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn area(name: &'static str, start: u64, pages: u64) -> VirtualMemoryArea {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        VirtualMemoryArea::new(name, VirtAddr::new(start), pages * PAGE_SIZE, flags, Backing::OnDemand)
+    }
+
+    #[test]
+    fn reserve_and_find() {
+        let mut registry = VmaRegistry::new();
+        registry.reserve(area("a", 0x10000, 4)).unwrap();
+        registry.reserve(area("b", 0x14000, 1)).unwrap();
+
+        assert_eq!(registry.find(VirtAddr::new(0x13fff)).map(|a| a.name), Some("a"));
+        assert_eq!(registry.find(VirtAddr::new(0x14000)).map(|a| a.name), Some("b"));
+        assert!(registry.find(VirtAddr::new(0x15000)).is_none());
+        assert!(registry.find(VirtAddr::new(0xffff)).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_areas() {
+        let mut registry = VmaRegistry::new();
+        registry.reserve(area("a", 0x10000, 4)).unwrap();
+
+        assert_eq!(registry.reserve(area("b", 0x12000, 4)), Err(VmaError::Overlapping("a")));
+        assert_eq!(registry.reserve(area("c", 0x20010, 1)), Err(VmaError::InvalidArea));
+        assert_eq!(registry.reserve(area("d", 0x20000, 0)), Err(VmaError::InvalidArea));
+    }
+
+    #[test]
+    fn release_frees_the_range() {
+        let mut registry = VmaRegistry::new();
+        registry.reserve(area("a", 0x10000, 4)).unwrap();
+        assert_eq!(registry.release(VirtAddr::new(0x11000)).err(), Some(VmaError::NotFound));
+
+        let released = registry.release(VirtAddr::new(0x10000)).unwrap();
+        assert_eq!(released.name, "a");
+        assert!(registry.reserve(area("b", 0x10000, 8)).is_ok());
+    }
+
+    #[test]
+    fn registry_capacity() {
+        let mut registry = VmaRegistry::new();
+        for i in 0..MAX_AREAS as u64 {
+            registry.reserve(area("a", 0x10000 + i * PAGE_SIZE, 1)).unwrap();
+        }
+        assert_eq!(registry.reserve(area("b", 0x100000, 1)), Err(VmaError::RegistryFull));
+    }
+}
@@ -1,5 +1,10 @@
+use core::fmt;
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use uart_16550::SerialPort;
 use spin::Mutex;
+use interrupts::{self, IrqError, SERIAL_IRQ};
+use vga_buffer;
 
 // lazy_static and spinlock used to reate a static reference to serial I/O port
 lazy_static! {
@@ -11,12 +16,128 @@ lazy_static! {
     };
 }
 
+/// Registers the handler of serial interrupts, which prints the characters the host
+/// sent to the screen. Characters arriving while the screen is locked are dropped. The
+/// port raises the interrupts for received data since its initialization.
+pub fn init_interrupts() -> Result<(), IrqError> {
+    interrupts::register_irq(SERIAL_IRQ, interrupt_handler)
+}
+
+fn interrupt_handler(_irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    // the ports are read directly, since the interrupted code may hold the SERIAL1 lock.
+    // Reading the data clears the interrupt
+    let data = Port::<u8>::new(0x3f8);
+    let line_status = Port::<u8>::new(0x3f8 + 5);
+    while unsafe { line_status.read() } & 1 != 0 {
+        let byte = unsafe { data.read() };
+        // the interrupted code may hold the screen lock as well, the character is
+        // dropped then instead of waiting for it forever
+        if byte.is_ascii_graphic() || byte == b' ' || byte == b'\n' {
+            vga_buffer::try_print(format_args!("{}", byte as char));
+        }
+    }
+}
+
 // fmt::Write trait already implemented for type SerialPort
 pub fn print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
+    if CAPTURING.load(Ordering::Acquire) {
+        Capture.write_fmt(args).expect("Capturing serial output failed");
+        return;
+    }
     SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
 }
 
+/// Prints to the host without taking the SERIAL1 lock, by writing to the port
+/// directly. For code that may have interrupted the holder of the lock, e.g. the
+/// crash report. The output can end up in the middle of the holder's.
+pub fn force_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    if CAPTURING.load(Ordering::Acquire) {
+        Capture.write_fmt(args).expect("Capturing serial output failed");
+        return;
+    }
+    RawPort.write_fmt(args).expect("Printing to serial failed");
+}
+
+// Writes to the port of SERIAL1 directly, the way SerialPort does
+struct RawPort;
+
+impl fmt::Write for RawPort {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        use x86_64::instructions::port::Port;
+
+        let mut data = Port::<u8>::new(0x3f8);
+        let line_status = Port::<u8>::new(0x3f8 + 5);
+        for &byte in text.as_bytes() {
+            // waits until the transmitter can take another byte
+            while unsafe { line_status.read() } & 0x20 == 0 {}
+            unsafe { data.write(byte) };
+        }
+        Ok(())
+    }
+}
+
+/// Bytes of serial output kept at most while capturing, the rest is dropped.
+pub const CAPTURE_SIZE: usize = 4096;
+
+// Serial output goes to CAPTURED instead of the port while set, see start_capture.
+// Writers reserve their bytes with CAPTURED_LEN, so no lock is taken
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+static CAPTURED_LEN: AtomicUsize = AtomicUsize::new(0);
+static mut CAPTURED: [u8; CAPTURE_SIZE] = [0; CAPTURE_SIZE];
+
+/// Keeps the serial output from now on instead of sending it to the host, until
+/// `stop_capture`. Lets test binaries check what the kernel printed, as the test runner
+/// only accepts output starting with "ok" or "failed".
+pub fn start_capture() {
+    CAPTURED_LEN.store(0, Ordering::Relaxed);
+    CAPTURING.store(true, Ordering::Release);
+}
+
+/// Sends the serial output to the host again and returns what was printed since
+/// `start_capture`, cut off after `CAPTURE_SIZE` bytes.
+pub fn stop_capture() -> Captured {
+    CAPTURING.store(false, Ordering::Release);
+    let len = core::cmp::min(CAPTURED_LEN.load(Ordering::Acquire), CAPTURE_SIZE);
+    // unsafe: the bytes up to len were written, nothing is captured any more
+    Captured { bytes: unsafe { CAPTURED }, len }
+}
+
+/// Serial output kept by `start_capture`.
+pub struct Captured {
+    bytes: [u8; CAPTURE_SIZE],
+    len: usize,
+}
+
+impl Captured {
+    /// The output up to the last complete character.
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.bytes[..self.len];
+        match str::from_utf8(bytes) {
+            Ok(text) => text,
+            // unsafe: the bytes up to the error are valid UTF-8
+            Err(err) => unsafe { str::from_utf8_unchecked(&bytes[..err.valid_up_to()]) },
+        }
+    }
+}
+
+// Appends to CAPTURED, dropping what doesn't fit
+struct Capture;
+
+impl fmt::Write for Capture {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let start = CAPTURED_LEN.fetch_add(text.len(), Ordering::AcqRel);
+        for (i, &byte) in text.as_bytes().iter().enumerate().take(CAPTURE_SIZE.saturating_sub(start)) {
+            // unsafe: the bytes were reserved above and are in bounds
+            unsafe { CAPTURED[start + i] = byte };
+        }
+        Ok(())
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -169,6 +169,19 @@ pub fn print(args: fmt::Arguments) {
     WRITER.lock().write_fmt(args).unwrap();
 }
 
+/// Prints like `print` unless the writer is locked, e.g. by the code an exception
+/// interrupted. Returns whether anything was printed.
+pub fn try_print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+    match WRITER.try_lock() {
+        Some(mut writer) => {
+            writer.write_fmt(args).unwrap();
+            true
+        }
+        None => false,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;       // import all items of parent module: vga_buffer